@@ -4,8 +4,15 @@
 
 -- DROP TABLE public.sipin_sips;
 
+-- For a monthly partitioned table, see ddl_partitioned.sql.
+-- Tables created with a serial4 row_id can be migrated with:
+-- ALTER TABLE public.sipin_sips ALTER COLUMN row_id TYPE int8;
+-- ALTER SEQUENCE public.sipin_sips_row_id_seq AS int8;
+-- Tables of an earlier version are brought up to date, with the other tables
+-- below, by upgrade.sql.
+
 CREATE TABLE public.sipin_sips (
-	row_id serial4 NOT NULL,
+	row_id bigserial NOT NULL,
 	correlation_id text NOT NULL, -- The correlation_id of this SIP-delivery.
 	bag_name text NULL, -- Filename by which this bag was delivered/uploaded to the ingest host.
 	cp_id text NULL, -- The ID for this CP within meemoo. Also known as OR-id.
@@ -13,6 +20,7 @@ CREATE TABLE public.sipin_sips (
 	md5_hash_sip text NULL, -- MD5 hash from the complete SIP package.
 	md5_hash_essence_manifest varchar(32) NULL, -- MD5 hash as calculated by meemoo.
 	md5_hash_essence_sidecar text NULL, -- MD5 hash as was delivered in the sidecar. No column constraints since any value can be provided in de sidecar.
+	checksum_mismatch bool GENERATED ALWAYS AS (lower(trim(md5_hash_essence_manifest)) <> lower(trim(md5_hash_essence_sidecar))) STORED, -- True if the manifest and sidecar MD5 hashes differ. NULL if either is unknown.
 	essence_filename text NULL, -- Filename of the main essence in this SIP.
 	essence_filesize int8 NULL, -- Filesize of the main essence in this SIP.
 	ingest_host text NULL, -- FQDN of the ingest host (scheme + host + domain).
@@ -21,10 +29,13 @@ CREATE TABLE public.sipin_sips (
 	bag_filesize int8 NULL, -- Filesize of the zipped bag.
 	pid bpchar(10) NULL, -- Persistent Identifier. The main ID of the object within meemoo.
 	mh_record_id bpchar(64) NULL, -- MediaHaven record ID.
+	sip_profile text NULL, -- The profile of the SIP, as determined by the mh-sip.create event.
 	first_event_date timestamptz NOT NULL, -- Datetime for the first event for this correlation ID.
 	last_event_type text NOT NULL, -- Last seen event type for this correlation ID.
 	last_event_date timestamptz NOT NULL, -- Datetime for the last event for this correlation ID.
 	status text NOT NULL, -- More human friendly status: correlates one-to-one with the last event type.
+	stalled bool NOT NULL DEFAULT false, -- True when no new event arrived within the timeout for the current status.
+	resolution text NULL, -- Set manually to resolved or ignored: the SIP is no longer flagged as stalled.
 	CONSTRAINT sipin_sips_correlation_id_key UNIQUE (correlation_id),
 	CONSTRAINT sipin_sips_mh_record_id_key UNIQUE (mh_record_id),
 	CONSTRAINT sipin_sips_pid_key UNIQUE (pid),
@@ -33,6 +44,9 @@ CREATE TABLE public.sipin_sips (
 CREATE INDEX sipin_sips_essence_filename_idx ON public.sipin_sips USING btree (essence_filename);
 CREATE INDEX sipin_sips_md5_hash_sip_idx ON public.sipin_sips USING btree (md5_hash_sip);
 CREATE INDEX sipin_sips_md5_hash_essence_manifest_idx ON public.sipin_sips USING btree (md5_hash_essence_manifest);
+CREATE INDEX sipin_sips_status_last_event_date_idx ON public.sipin_sips USING btree (status, last_event_date);
+CREATE INDEX sipin_sips_cp_id_first_event_date_idx ON public.sipin_sips USING btree (cp_id, first_event_date);
+CREATE INDEX sipin_sips_local_id_idx ON public.sipin_sips USING btree (local_id);
 
 -- Column comments
 
@@ -43,6 +57,7 @@ COMMENT ON COLUMN public.sipin_sips.local_id IS 'The CP-provided main ID of the
 COMMENT ON COLUMN public.sipin_sips.md5_hash_sip IS 'MD5 hash from the complete SIP package.';
 COMMENT ON COLUMN public.sipin_sips.md5_hash_essence_manifest IS 'MD5 hash as calculated by meemoo.';
 COMMENT ON COLUMN public.sipin_sips.md5_hash_essence_sidecar IS 'MD5 hash as was delivered in the sidecar. No column constraints since any value can be provided in de sidecar.';
+COMMENT ON COLUMN public.sipin_sips.checksum_mismatch IS 'True if the manifest and sidecar MD5 hashes differ. NULL if either is unknown.';
 COMMENT ON COLUMN public.sipin_sips.essence_filename IS 'Filename of the main essence in this SIP.';
 COMMENT ON COLUMN public.sipin_sips.essence_filesize IS 'Filesize of the main essence in this SIP.';
 COMMENT ON COLUMN public.sipin_sips.ingest_host IS 'FQDN of the ingest host (scheme + host + domain).';
@@ -51,7 +66,144 @@ COMMENT ON COLUMN public.sipin_sips.ingest_path_or_key IS 'Fully qualified path
 COMMENT ON COLUMN public.sipin_sips.bag_filesize IS 'Filesize of the zipped bag.';
 COMMENT ON COLUMN public.sipin_sips.pid IS 'Persistent Identifier. The main ID of the object within meemoo.';
 COMMENT ON COLUMN public.sipin_sips.mh_record_id IS 'MediaHaven record ID.';
+COMMENT ON COLUMN public.sipin_sips.sip_profile IS 'The profile of the SIP, as determined by the mh-sip.create event.';
 COMMENT ON COLUMN public.sipin_sips.first_event_date IS 'Datetime for the first event for this correlation ID.';
 COMMENT ON COLUMN public.sipin_sips.last_event_type IS 'Last seen event type for this correlation ID.';
 COMMENT ON COLUMN public.sipin_sips.last_event_date IS 'Datetime for the last event for this correlation ID.';
-COMMENT ON COLUMN public.sipin_sips.status IS 'More human friendly status: correlates one-to-one with the last event type.';
\ No newline at end of file
+COMMENT ON COLUMN public.sipin_sips.status IS 'More human friendly status: correlates one-to-one with the last event type.';
+COMMENT ON COLUMN public.sipin_sips.stalled IS 'True when no new event arrived within the timeout for the current status.';
+COMMENT ON COLUMN public.sipin_sips.resolution IS 'Set manually to resolved or ignored: the SIP is no longer flagged as stalled.';
+
+-- public.sipin_sip_stages definition
+
+-- Drop table
+
+-- DROP TABLE public.sipin_sip_stages;
+
+CREATE TABLE public.sipin_sip_stages (
+	correlation_id text NOT NULL, -- The correlation_id of the SIP-delivery.
+	stage text NOT NULL, -- The pipeline stage, eg. unzip or validate_xsd.
+	event_type text NOT NULL, -- Type of the event that marked the end of the stage.
+	event_date timestamptz NOT NULL, -- Datetime at which the stage ended.
+	outcome text NULL, -- Outcome of the stage, as reported by the event.
+	CONSTRAINT sipin_sip_stages_pkey PRIMARY KEY (correlation_id, stage)
+);
+CREATE INDEX sipin_sip_stages_event_date_idx ON public.sipin_sip_stages USING btree (event_date);
+
+-- Column comments
+
+COMMENT ON COLUMN public.sipin_sip_stages.correlation_id IS 'The correlation_id of the SIP-delivery.';
+COMMENT ON COLUMN public.sipin_sip_stages.stage IS 'The pipeline stage, eg. unzip or validate_xsd.';
+COMMENT ON COLUMN public.sipin_sip_stages.event_type IS 'Type of the event that marked the end of the stage.';
+COMMENT ON COLUMN public.sipin_sip_stages.event_date IS 'Datetime at which the stage ended.';
+COMMENT ON COLUMN public.sipin_sip_stages.outcome IS 'Outcome of the stage, as reported by the event.';
+
+-- public.sipin_cp_mappings definition
+
+-- Drop table
+
+-- DROP TABLE public.sipin_cp_mappings;
+
+CREATE TABLE public.sipin_cp_mappings (
+	bucket text NOT NULL, -- Bucket in which the CP delivers its SIPs.
+	key_prefix text NOT NULL DEFAULT '', -- Prefix of the keys of the CP's SIPs within the bucket. Empty for the whole bucket.
+	cp_id text NOT NULL, -- The ID for the CP within meemoo. Also known as OR-id.
+	CONSTRAINT sipin_cp_mappings_pkey PRIMARY KEY (bucket, key_prefix)
+);
+
+-- Column comments
+
+COMMENT ON COLUMN public.sipin_cp_mappings.bucket IS 'Bucket in which the CP delivers its SIPs.';
+COMMENT ON COLUMN public.sipin_cp_mappings.key_prefix IS 'Prefix of the keys of the CP''s SIPs within the bucket. Empty for the whole bucket.';
+COMMENT ON COLUMN public.sipin_cp_mappings.cp_id IS 'The ID for the CP within meemoo. Also known as OR-id.';
+
+-- public.sipin_outbox definition
+
+-- Drop table
+
+-- DROP TABLE public.sipin_outbox;
+
+CREATE TABLE public.sipin_outbox (
+	id bigserial NOT NULL,
+	topic text NOT NULL, -- Pulsar topic on which the event is to be published.
+	payload jsonb NOT NULL, -- The CloudEvent.
+	created_at timestamptz NOT NULL DEFAULT now(), -- Datetime at which the event was added, in the transaction that caused it.
+	published_at timestamptz NULL, -- Datetime at which the broker acknowledged the event. NULL while pending.
+	CONSTRAINT sipin_outbox_pkey PRIMARY KEY (id)
+);
+CREATE INDEX sipin_outbox_pending_idx ON public.sipin_outbox USING btree (id) WHERE published_at IS NULL;
+CREATE INDEX sipin_outbox_published_at_idx ON public.sipin_outbox USING btree (published_at);
+
+-- Column comments
+
+COMMENT ON COLUMN public.sipin_outbox.topic IS 'Pulsar topic on which the event is to be published.';
+COMMENT ON COLUMN public.sipin_outbox.payload IS 'The CloudEvent.';
+COMMENT ON COLUMN public.sipin_outbox.created_at IS 'Datetime at which the event was added, in the transaction that caused it.';
+COMMENT ON COLUMN public.sipin_outbox.published_at IS 'Datetime at which the broker acknowledged the event. NULL while pending.';
+
+-- public.sipin_webhook_deliveries definition
+
+-- Drop table
+
+-- DROP TABLE public.sipin_webhook_deliveries;
+
+CREATE TABLE public.sipin_webhook_deliveries (
+	id bigserial NOT NULL,
+	correlation_id text NOT NULL, -- The correlation_id of the SIP-delivery.
+	cp_id text NOT NULL, -- The CP whose webhook is called.
+	url text NOT NULL, -- URL of the webhook.
+	payload jsonb NOT NULL, -- The status change that is delivered.
+	status text NOT NULL DEFAULT 'pending', -- pending, delivered or failed (after the maximum number of attempts).
+	attempts int4 NOT NULL DEFAULT 0, -- Number of delivery attempts so far.
+	next_attempt_at timestamptz NOT NULL DEFAULT now(), -- Datetime of the next attempt, while pending.
+	response_status int4 NULL, -- HTTP status of the successful attempt.
+	last_error text NULL, -- Error of the last failed attempt.
+	created_at timestamptz NOT NULL DEFAULT now(), -- Datetime at which the delivery was added.
+	delivered_at timestamptz NULL, -- Datetime of the successful attempt.
+	CONSTRAINT sipin_webhook_deliveries_pkey PRIMARY KEY (id)
+);
+CREATE INDEX sipin_webhook_deliveries_pending_idx ON public.sipin_webhook_deliveries USING btree (next_attempt_at) WHERE status = 'pending';
+CREATE INDEX sipin_webhook_deliveries_correlation_id_idx ON public.sipin_webhook_deliveries USING btree (correlation_id);
+
+-- Column comments
+
+COMMENT ON COLUMN public.sipin_webhook_deliveries.correlation_id IS 'The correlation_id of the SIP-delivery.';
+COMMENT ON COLUMN public.sipin_webhook_deliveries.cp_id IS 'The CP whose webhook is called.';
+COMMENT ON COLUMN public.sipin_webhook_deliveries.url IS 'URL of the webhook.';
+COMMENT ON COLUMN public.sipin_webhook_deliveries.payload IS 'The status change that is delivered.';
+COMMENT ON COLUMN public.sipin_webhook_deliveries.status IS 'pending, delivered or failed (after the maximum number of attempts).';
+COMMENT ON COLUMN public.sipin_webhook_deliveries.attempts IS 'Number of delivery attempts so far.';
+COMMENT ON COLUMN public.sipin_webhook_deliveries.next_attempt_at IS 'Datetime of the next attempt, while pending.';
+COMMENT ON COLUMN public.sipin_webhook_deliveries.response_status IS 'HTTP status of the successful attempt.';
+COMMENT ON COLUMN public.sipin_webhook_deliveries.last_error IS 'Error of the last failed attempt.';
+COMMENT ON COLUMN public.sipin_webhook_deliveries.created_at IS 'Datetime at which the delivery was added.';
+COMMENT ON COLUMN public.sipin_webhook_deliveries.delivered_at IS 'Datetime of the successful attempt.';
+
+-- public.sipin_sip_audit definition
+
+-- Drop table
+
+-- DROP TABLE public.sipin_sip_audit;
+
+CREATE TABLE public.sipin_sip_audit (
+	id bigserial NOT NULL,
+	correlation_id text NOT NULL, -- The correlation_id of the corrected SIP-delivery.
+	action text NOT NULL, -- set_status, resolve, ignore or reopen.
+	actor text NOT NULL, -- Who made the correction.
+	reason text NOT NULL, -- Why the correction was made.
+	before jsonb NOT NULL, -- The sipin_sips row before the correction.
+	after jsonb NOT NULL, -- The sipin_sips row after the correction.
+	created_at timestamptz NOT NULL DEFAULT now(), -- Datetime of the correction.
+	CONSTRAINT sipin_sip_audit_pkey PRIMARY KEY (id)
+);
+CREATE INDEX sipin_sip_audit_correlation_id_idx ON public.sipin_sip_audit USING btree (correlation_id);
+
+-- Column comments
+
+COMMENT ON COLUMN public.sipin_sip_audit.correlation_id IS 'The correlation_id of the corrected SIP-delivery.';
+COMMENT ON COLUMN public.sipin_sip_audit.action IS 'set_status, resolve, ignore or reopen.';
+COMMENT ON COLUMN public.sipin_sip_audit.actor IS 'Who made the correction.';
+COMMENT ON COLUMN public.sipin_sip_audit.reason IS 'Why the correction was made.';
+COMMENT ON COLUMN public.sipin_sip_audit.before IS 'The sipin_sips row before the correction.';
+COMMENT ON COLUMN public.sipin_sip_audit.after IS 'The sipin_sips row after the correction.';
+COMMENT ON COLUMN public.sipin_sip_audit.created_at IS 'Datetime of the correction.';
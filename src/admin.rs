@@ -0,0 +1,249 @@
+use chrono::Utc;
+use serde::Deserialize;
+use serde_json::Value;
+use tokio_postgres::{types::ToSql, Client};
+use crate::changes::{ChangeSinks, StatusChange, STATUSES};
+
+/// The `last_event_type` of a SIP whose status was set by a correction.
+pub const CORRECTION_EVENT_TYPE: &str = "be.meemoo.sipin.sip.status.corrected";
+
+/// A manual correction of a SIP.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum Correction {
+    /// Set the status, one of `STATUSES`. Events older than the correction
+    /// don't overwrite it, newer ones do.
+    SetStatus { status: String },
+    /// Mark the SIP as resolved: it is no longer flagged as stalled.
+    Resolve,
+    /// Mark the SIP as ignored: it is no longer flagged as stalled.
+    Ignore,
+    /// Clear the resolution again.
+    Reopen,
+}
+
+/// A correction together with who made it and why, eg.:
+/// `{"action": "set_status", "status": "AIP_DELIVERED_TO_MAM", "actor": "jdoe", "reason": "ticket 123"}`
+#[derive(Deserialize, Debug, Clone)]
+pub struct CorrectionRequest {
+    #[serde(flatten)]
+    pub correction: Correction,
+    pub actor: String,
+    pub reason: String,
+}
+
+impl Correction {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Correction::SetStatus { .. } => "set_status",
+            Correction::Resolve => "resolve",
+            Correction::Ignore => "ignore",
+            Correction::Reopen => "reopen",
+        }
+    }
+
+    /// Returns false for a status that no event sets.
+    pub fn is_valid(&self) -> bool {
+        self.status().is_none_or(|status| STATUSES.contains(&status))
+    }
+
+    /// The `SET` clause of the update, which can refer to the new status as
+    /// `$2` and to `CORRECTION_EVENT_TYPE` as `$3`.
+    pub fn assignments(&self) -> &'static str {
+        match self {
+            Correction::SetStatus { .. } => "status = $2, last_event_type = $3, last_event_date = GREATEST(last_event_date, now()), stalled = false",
+            Correction::Resolve => "resolution = 'resolved', stalled = false",
+            Correction::Ignore => "resolution = 'ignored', stalled = false",
+            Correction::Reopen => "resolution = NULL",
+        }
+    }
+
+    fn status(&self) -> Option<&str> {
+        match self {
+            Correction::SetStatus { status } => Some(status),
+            _ => None,
+        }
+    }
+}
+
+/// Apply a correction to a SIP and record it in `sipin_sip_audit`, with the
+/// row before and after. Returns the row after the correction, or `None` if
+/// there is no SIP with the correlation_id.
+///
+/// Like an upsert for an event, the correction runs in a transaction that
+/// first takes the advisory lock on the correlation_id, and a change of the
+/// status is recorded in `sinks` in that same transaction. A partitioned
+/// `sipin_sips` can hold several rows for a correlation_id, eg. inserted by
+/// hand; nothing is corrected then.
+pub async fn apply(
+    client: &mut Client,
+    sinks: &ChangeSinks,
+    correlation_id: &str,
+    request: &CorrectionRequest,
+) -> Result<Option<Value>, anyhow::Error> {
+    let transaction = client.transaction().await?;
+    transaction.execute("SELECT pg_advisory_xact_lock(hashtext($1))", &[&correlation_id]).await?;
+    let rows = transaction.query(
+        "SELECT row_id, to_jsonb(s) - 'row_id', status, last_event_date FROM sipin_sips s
+        WHERE correlation_id = $1 FOR UPDATE", &[&correlation_id],
+    ).await?;
+    let before = match rows.as_slice() {
+        [] => return Ok(None),
+        [row] => row,
+        rows => return Err(anyhow::anyhow!("{} rows with correlation_id {}", rows.len(), correlation_id)),
+    };
+    let row_id: i64 = before.get(0);
+    let status = request.correction.status();
+    let mut params: Vec<&(dyn ToSql + Sync)> = vec![&row_id];
+    if let Some(status) = &status {
+        params.push(status);
+        params.push(&CORRECTION_EVENT_TYPE);
+    }
+    let statement = format!(
+        "UPDATE sipin_sips s SET {} WHERE row_id = $1
+        RETURNING to_jsonb(s) - 'row_id', status, first_event_date, cp_id, pid",
+        request.correction.assignments(),
+    );
+    let after = transaction.query_one(statement.as_str(), &params).await?;
+    let row: Value = after.get(0);
+    transaction.execute(
+        "INSERT INTO sipin_sip_audit (correlation_id, action, actor, reason, before, after)
+        VALUES ($1, $2, $3, $4, $5, $6)", &[
+            &correlation_id,
+            &request.correction.name(),
+            &request.actor,
+            &request.reason,
+            &before.get::<_, Value>(1),
+            &row,
+        ],
+    ).await?;
+    let old_status: String = before.get(2);
+    let change = status.filter(|status| *status != old_status).map(|status| StatusChange {
+        correlation_id: correlation_id.to_string(),
+        old_status: Some(old_status.clone()),
+        new_status: status.to_string(),
+        cp_id: after.get(3),
+        pid: after.get(4),
+        time: Utc::now(),
+        outcome: String::from("success"),
+        first_event_date: after.get(2),
+        old_event_date: before.get(3),
+        stage: None,
+    });
+    if let Some(change) = &change {
+        sinks.record(&transaction, change).await?;
+    }
+    transaction.commit().await?;
+    if let Some(change) = change {
+        sinks.committed(change);
+    }
+    Ok(Some(row))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use tokio::sync::{broadcast, Notify};
+    use crate::webhooks::{Webhook, Webhooks};
+
+    fn sinks() -> ChangeSinks {
+        let hook = Webhook {
+            cp_id: String::from("OR-rf5kf25"),
+            url: String::from("http://localhost/hook"),
+            secret: String::from("secret"),
+        };
+        ChangeSinks {
+            broadcast: broadcast::channel(16).0,
+            notify_channel: String::new(),
+            output_topic: String::from("public/sipin/status"),
+            outbox_wake: Arc::new(Notify::new()),
+            webhooks: Arc::new(Webhooks { hooks: HashMap::from([(hook.cp_id.clone(), hook)]) }),
+            webhook_wake: Arc::new(Notify::new()),
+        }
+    }
+
+    fn request(correction: Correction) -> CorrectionRequest {
+        CorrectionRequest { correction, actor: String::from("jdoe"), reason: String::from("ticket 123") }
+    }
+
+    async fn add(client: &Client, correlation_id: &str, first_event_date: &str) {
+        client.execute(
+            "INSERT INTO sipin_sips (correlation_id, cp_id, first_event_date, last_event_type, last_event_date, status)
+            VALUES ($1, 'OR-rf5kf25', $2::text::timestamptz, 'be.meemoo.sipin.bag.unzip', $2::text::timestamptz, 'BAG_UNZIPPED')",
+            &[&correlation_id, &first_event_date],
+        ).await.unwrap();
+    }
+
+    async fn count(client: &Client, table: &str) -> i64 {
+        client.query_one(format!("SELECT count(*) FROM {}", table).as_str(), &[]).await.unwrap().get(0)
+    }
+
+    #[tokio::test]
+    #[ignore = "needs PULSAR2DB_TEST_POSTGRES"]
+    async fn apply_set_status_records_change() {
+        let mut client = crate::testing::database("apply_set_status_records_change", crate::testing::DDL).await;
+        add(&client, "abc", "2024-05-20T10:00:00Z").await;
+        let sinks = sinks();
+        let mut changes = sinks.broadcast.subscribe();
+        let status = String::from("AIP_DELIVERED_TO_MAM");
+        let row = apply(&mut client, &sinks, "abc", &request(Correction::SetStatus { status })).await.unwrap().unwrap();
+        assert_eq!(row["status"], "AIP_DELIVERED_TO_MAM");
+        assert_eq!(row["last_event_type"], CORRECTION_EVENT_TYPE);
+        let audit = client.query_one("SELECT action, before->>'status', after->>'status' FROM sipin_sip_audit", &[]).await.unwrap();
+        assert_eq!(audit.get::<_, &str>(0), "set_status");
+        assert_eq!(audit.get::<_, &str>(1), "BAG_UNZIPPED");
+        assert_eq!(audit.get::<_, &str>(2), "AIP_DELIVERED_TO_MAM");
+        assert_eq!(count(&client, "sipin_outbox").await, 1);
+        assert_eq!(count(&client, "sipin_webhook_deliveries").await, 1);
+        let change = changes.try_recv().unwrap();
+        assert_eq!(change.old_status.as_deref(), Some("BAG_UNZIPPED"));
+        assert_eq!(change.new_status, "AIP_DELIVERED_TO_MAM");
+    }
+    #[tokio::test]
+    #[ignore = "needs PULSAR2DB_TEST_POSTGRES"]
+    async fn apply_resolve_changes_no_status() {
+        let mut client = crate::testing::database("apply_resolve_changes_no_status", crate::testing::DDL).await;
+        add(&client, "abc", "2024-05-20T10:00:00Z").await;
+        let row = apply(&mut client, &sinks(), "abc", &request(Correction::Resolve)).await.unwrap().unwrap();
+        assert_eq!(row["resolution"], "resolved");
+        assert_eq!(row["last_event_type"], "be.meemoo.sipin.bag.unzip");
+        assert_eq!(count(&client, "sipin_sip_audit").await, 1);
+        assert_eq!(count(&client, "sipin_outbox").await, 0);
+        assert!(apply(&mut client, &sinks(), "other", &request(Correction::Resolve)).await.unwrap().is_none());
+        assert_eq!(count(&client, "sipin_sip_audit").await, 1);
+    }
+    #[tokio::test]
+    #[ignore = "needs PULSAR2DB_TEST_POSTGRES"]
+    async fn apply_to_several_rows_corrects_nothing() {
+        let mut client = crate::testing::partitioned_database("apply_to_several_rows_corrects_nothing").await;
+        add(&client, "abc", "2024-05-20T10:00:00Z").await;
+        add(&client, "abc", "2024-06-20T10:00:00Z").await;
+        assert!(apply(&mut client, &sinks(), "abc", &request(Correction::Ignore)).await.is_err());
+        assert_eq!(count(&client, "sipin_sips WHERE resolution IS NOT NULL").await, 0);
+        assert_eq!(count(&client, "sipin_sip_audit").await, 0);
+    }
+    #[test]
+    fn is_valid_for_known_statuses() {
+        assert!(Correction::SetStatus { status: String::from("BAG_VALIDATED") }.is_valid());
+        assert!(!Correction::SetStatus { status: String::from("BAG_LOST") }.is_valid());
+        assert!(Correction::Reopen.is_valid());
+    }
+    #[test]
+    fn deserialize_requests() {
+        let request: CorrectionRequest = serde_json::from_str(
+            r#"{"action": "set_status", "status": "AIP_DELIVERED_TO_MAM", "actor": "jdoe", "reason": "ticket 123"}"#,
+        ).unwrap();
+        assert_eq!(request.correction, Correction::SetStatus { status: String::from("AIP_DELIVERED_TO_MAM") });
+        assert_eq!(request.actor, "jdoe");
+        let request: CorrectionRequest = serde_json::from_str(r#"{"action": "reopen", "actor": "jdoe", "reason": "new delivery"}"#).unwrap();
+        assert_eq!(request.correction, Correction::Reopen);
+    }
+    #[test]
+    fn deserialize_rejects_missing_fields() {
+        assert!(serde_json::from_str::<CorrectionRequest>(r#"{"action": "set_status", "actor": "jdoe", "reason": ""}"#).is_err());
+        assert!(serde_json::from_str::<CorrectionRequest>(r#"{"action": "resolve", "reason": "done"}"#).is_err());
+        assert!(serde_json::from_str::<CorrectionRequest>(r#"{"action": "delete", "actor": "jdoe", "reason": ""}"#).is_err());
+    }
+}
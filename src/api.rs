@@ -0,0 +1,302 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::sse::{Event, KeepAlive, Sse},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use chrono::{DateTime, Utc};
+use futures::Stream;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use tokio::sync::{broadcast::error::RecvError, Mutex};
+use tokio_postgres::{types::ToSql, Client};
+use crate::admin::{self, CorrectionRequest};
+use crate::changes::{ChangeSinks, StatusChange};
+use crate::SharedClient;
+
+// Number of SIPs returned per page, unless asked otherwise, and the maximum.
+const DEFAULT_LIMIT: i64 = 100;
+const MAX_LIMIT: i64 = 1000;
+
+/// The filters for listing SIPs. All given filters must match.
+///
+/// `md5` matches either the MD5 hash of the SIP or the one of its essence as
+/// calculated by meemoo. `from` and `to` bound the `first_event_date`.
+#[derive(Deserialize, Debug, Default)]
+pub struct SipQuery {
+    pub pid: Option<String>,
+    pub local_id: Option<String>,
+    pub essence_filename: Option<String>,
+    pub md5: Option<String>,
+    pub cp_id: Option<String>,
+    pub status: Option<String>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+impl SipQuery {
+    /// The `WHERE` clause for the filters, with its parameters.
+    pub fn filter(&self) -> (String, Vec<&(dyn ToSql + Sync)>) {
+        let mut conditions: Vec<String> = Vec::new();
+        let mut params: Vec<&(dyn ToSql + Sync)> = Vec::new();
+        let columns = [
+            ("pid", &self.pid),
+            ("local_id", &self.local_id),
+            ("essence_filename", &self.essence_filename),
+            ("cp_id", &self.cp_id),
+            ("status", &self.status),
+        ];
+        for (column, value) in columns {
+            if let Some(value) = value {
+                params.push(value);
+                conditions.push(format!("{} = ${}", column, params.len()));
+            }
+        }
+        if let Some(md5) = &self.md5 {
+            params.push(md5);
+            conditions.push(format!("(md5_hash_sip = ${0} OR md5_hash_essence_manifest = ${0})", params.len()));
+        }
+        if let Some(from) = &self.from {
+            params.push(from);
+            conditions.push(format!("first_event_date >= ${}", params.len()));
+        }
+        if let Some(to) = &self.to {
+            params.push(to);
+            conditions.push(format!("first_event_date < ${}", params.len()));
+        }
+        match conditions.is_empty() {
+            true => (String::new(), params),
+            false => (format!("WHERE {}", conditions.join(" AND ")), params),
+        }
+    }
+
+    /// The page size, capped at `MAX_LIMIT`.
+    pub fn limit(&self) -> i64 {
+        self.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT)
+    }
+
+    pub fn offset(&self) -> i64 {
+        self.offset.unwrap_or(0).max(0)
+    }
+}
+
+/// The filter for the stream of status changes.
+#[derive(Deserialize, Debug, Default)]
+pub struct ChangesQuery {
+    pub cp_id: Option<String>,
+}
+
+impl ChangesQuery {
+    pub fn matches(&self, change: &StatusChange) -> bool {
+        self.cp_id.is_none() || self.cp_id == change.cp_id
+    }
+}
+
+/// What the API handlers share: a Postgres connection, another one for the
+/// transactions of the admin corrections (reopened with the parameters of the
+/// first when lost), where the status changes go (and are broadcast from),
+/// and the token for the admin endpoints (empty to disable them).
+#[derive(Clone)]
+pub struct ApiState {
+    pub client: Arc<SharedClient>,
+    pub admin_client: Arc<Mutex<Client>>,
+    pub sinks: Arc<ChangeSinks>,
+    pub admin_token: String,
+}
+
+/// Returns true if the request carries the admin token as bearer token.
+///
+/// The digests of the tokens are compared in constant time, so the time
+/// taken doesn't tell how much of a token was right.
+pub fn authorized(headers: &HeaderMap, admin_token: &str) -> bool {
+    let expected = Sha256::digest(format!("Bearer {}", admin_token));
+    let actual = Sha256::digest(headers.get(header::AUTHORIZATION).map(|value| value.as_bytes()).unwrap_or_default());
+    let difference = expected.iter().zip(actual.iter()).fold(0, |difference, (a, b)| difference | (a ^ b));
+    !admin_token.is_empty() && difference == 0
+}
+
+/// An error while handling a request, logged and returned as a 500.
+pub struct ApiError(anyhow::Error);
+
+impl<E: Into<anyhow::Error>> From<E> for ApiError {
+    fn from(error: E) -> ApiError {
+        ApiError(error.into())
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        log::error!("API error: {:?}", self.0);
+        (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({"error": "internal error"}))).into_response()
+    }
+}
+
+async fn get_sip(
+    State(state): State<ApiState>,
+    Path(correlation_id): Path<String>,
+) -> Result<Response, ApiError> {
+    let row = state.client.get().await?.query_opt(
+        "SELECT to_jsonb(s) - 'row_id' FROM sipin_sips s WHERE correlation_id = $1",
+        &[&correlation_id],
+    ).await?;
+    Ok(match row {
+        Some(row) => Json(row.get::<_, Value>(0)).into_response(),
+        None => (StatusCode::NOT_FOUND, Json(json!({"error": "not found"}))).into_response(),
+    })
+}
+
+async fn list_sips(
+    State(state): State<ApiState>,
+    Query(query): Query<SipQuery>,
+) -> Result<Json<Value>, ApiError> {
+    let (filter, mut params) = query.filter();
+    let (limit, offset) = (query.limit(), query.offset());
+    params.push(&limit);
+    params.push(&offset);
+    let statement = format!(
+        "SELECT to_jsonb(s) - 'row_id' FROM sipin_sips s {}
+        ORDER BY first_event_date DESC, correlation_id
+        LIMIT ${} OFFSET ${}",
+        filter, params.len() - 1, params.len(),
+    );
+    let sips: Vec<Value> = state.client.get().await?.query(statement.as_str(), &params).await?
+        .iter()
+        .map(|row| row.get(0))
+        .collect();
+    Ok(Json(json!({"sips": sips, "limit": limit, "offset": offset})))
+}
+
+/// Stream the status changes as Server-Sent Events of type `status`, with
+/// the `StatusChange` as JSON data. Clients that fall behind miss changes.
+async fn stream_changes(
+    State(state): State<ApiState>,
+    Query(query): Query<ChangesQuery>,
+) -> Sse<impl Stream<Item = Result<Event, serde_json::Error>>> {
+    let stream = futures::stream::unfold(
+        (state.sinks.broadcast.subscribe(), query),
+        |(mut receiver, query)| async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(change) if query.matches(&change) => {
+                        let event = Event::default().event("status").json_data(&change);
+                        return Some((event, (receiver, query)));
+                    },
+                    Ok(_) => continue,
+                    Err(RecvError::Lagged(missed)) => log::warn!("Status change stream fell behind, missed {} changes", missed),
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        },
+    );
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+/// Apply a manual correction to a SIP, see `admin::apply`.
+async fn correct_sip(
+    State(state): State<ApiState>,
+    Path(correlation_id): Path<String>,
+    headers: HeaderMap,
+    Json(request): Json<CorrectionRequest>,
+) -> Result<Response, ApiError> {
+    if !authorized(&headers, &state.admin_token) {
+        return Ok((StatusCode::UNAUTHORIZED, Json(json!({"error": "unauthorized"}))).into_response());
+    }
+    if request.actor.trim().is_empty() || request.reason.trim().is_empty() {
+        return Ok((StatusCode::BAD_REQUEST, Json(json!({"error": "actor and reason are required"}))).into_response());
+    }
+    if !request.correction.is_valid() {
+        return Ok((StatusCode::BAD_REQUEST, Json(json!({"error": "unknown status"}))).into_response());
+    }
+    log::info!("{} applies {} to correlation_id {}: {}", &request.actor, request.correction.name(), &correlation_id, &request.reason);
+    let mut client = state.admin_client.lock().await;
+    if client.is_closed() {
+        log::warn!("Lost the admin connection to Postgres, reconnecting");
+        *client = state.client.connect().await?;
+    }
+    Ok(match admin::apply(&mut client, &state.sinks, &correlation_id, &request).await? {
+        Some(row) => Json(row).into_response(),
+        None => (StatusCode::NOT_FOUND, Json(json!({"error": "not found"}))).into_response(),
+    })
+}
+
+/// The API on `sipin_sips`:
+///
+/// - `GET /api/sips/:correlation_id`: a single SIP.
+/// - `GET /api/sips`: SIPs matching the filters in `SipQuery`, most recent
+///   first, paginated with `limit` and `offset`.
+/// - `GET /api/changes`: the live stream of status changes, optionally
+///   filtered on `cp_id`.
+/// - `POST /api/admin/sips/:correlation_id`: a manual correction, see
+///   `CorrectionRequest`. Requires the admin token.
+pub fn router(state: ApiState) -> Router {
+    Router::new()
+        .route("/api/sips", get(list_sips))
+        .route("/api/sips/:correlation_id", get(get_sip))
+        .route("/api/changes", get(stream_changes))
+        .route("/api/admin/sips/:correlation_id", post(correct_sip))
+        .with_state(state)
+}
+
+/// Serve the API on the given port.
+pub async fn serve(state: ApiState, port: u16) -> Result<(), anyhow::Error> {
+    let addr = SocketAddr::from(([0, 0, 0, 0], port));
+    log::info!("Serving the API on {}", addr);
+    axum::Server::bind(&addr)
+        .serve(router(state).into_make_service())
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn filter_without_filters() {
+        let query = SipQuery::default();
+        let (filter, params) = query.filter();
+        assert_eq!(filter, "");
+        assert!(params.is_empty());
+    }
+    #[test]
+    fn filter_numbers_parameters() {
+        let query = SipQuery {
+            cp_id: Some(String::from("OR-rf5kf25")),
+            md5: Some(String::from("0123456789abcdef0123456789abcdef")),
+            from: Some(Utc::now()),
+            ..SipQuery::default()
+        };
+        let (filter, params) = query.filter();
+        assert_eq!(filter, "WHERE cp_id = $1 AND (md5_hash_sip = $2 OR md5_hash_essence_manifest = $2) AND first_event_date >= $3");
+        assert_eq!(params.len(), 3);
+    }
+    #[test]
+    fn limit_is_capped() {
+        assert_eq!(SipQuery::default().limit(), DEFAULT_LIMIT);
+        assert_eq!(SipQuery { limit: Some(100000), ..SipQuery::default() }.limit(), MAX_LIMIT);
+        assert_eq!(SipQuery { offset: Some(-5), ..SipQuery::default() }.offset(), 0);
+    }
+    #[test]
+    fn changes_query_matches_cp_id() {
+        let change = crate::testing::change("S3_OBJECT_CREATED", "success");
+        assert!(ChangesQuery::default().matches(&change));
+        assert!(ChangesQuery { cp_id: Some(String::from("OR-rf5kf25")) }.matches(&change));
+        assert!(!ChangesQuery { cp_id: Some(String::from("OR-w66976m")) }.matches(&change));
+    }
+    #[test]
+    fn authorized_with_bearer_token() {
+        let mut headers = HeaderMap::new();
+        assert!(!authorized(&headers, "token"));
+        headers.insert(header::AUTHORIZATION, "Bearer token".parse().unwrap());
+        assert!(authorized(&headers, "token"));
+        assert!(!authorized(&headers, "other"));
+        assert!(!authorized(&headers, "tok"));
+        assert!(!authorized(&headers, ""));
+    }
+}
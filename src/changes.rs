@@ -0,0 +1,250 @@
+use std::sync::Arc;
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::sync::{broadcast, Notify};
+use tokio_postgres::{AsyncMessage, NoTls, Row, Transaction};
+use crate::webhooks::Webhooks;
+use crate::{outbox, webhooks, CloudEvent};
+
+/// Type of the CloudEvents published for status changes.
+pub const STATUS_CHANGED_EVENT_TYPE: &str = "be.meemoo.sipin.sip.status.changed";
+
+/// The statuses the events set, in the order of the pipeline.
+pub const STATUSES: [&str; 9] = [
+    "S3_OBJECT_CREATED",
+    "SIP_CREATED",
+    "BAG_TRANSFERRED_TO_SIPIN",
+    "BAG_UNZIPPED",
+    "BAG_VALIDATED",
+    "SIP_VALIDATED",
+    "AIP_CREATED",
+    "MH-SIP_CREATED",
+    "AIP_DELIVERED_TO_MAM",
+];
+
+/// A change of the status of a SIP, as committed to `sipin_sips`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct StatusChange {
+    pub correlation_id: String,
+    /// `None` when the row was created by the change.
+    pub old_status: Option<String>,
+    pub new_status: String,
+    pub cp_id: Option<String>,
+    pub pid: Option<String>,
+    /// The time and outcome of the event that caused the change.
+    pub time: DateTime<Utc>,
+    pub outcome: String,
+    pub first_event_date: DateTime<Utc>,
+    /// The time of the event that set the old status.
+    pub old_event_date: Option<DateTime<Utc>>,
+    /// The stage that failed, when a failed stage is reported without a
+    /// change of the status: `old_status` is then `new_status`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stage: Option<String>,
+}
+
+impl StatusChange {
+    /// The change described by a row returned by the upsert, with columns
+    /// `old_status`, `old_event_date`, `status`, `first_event_date`, `cp_id`
+    /// and `pid`, for `event`. Returns `None` if the status stayed the same.
+    pub fn from_row(row: &Row, event: &CloudEvent) -> Option<StatusChange> {
+        let old_status: Option<String> = row.get("old_status");
+        let new_status: String = row.get("status");
+        if old_status.as_ref() == Some(&new_status) {
+            return None;
+        }
+        Some(StatusChange {
+            correlation_id: event.correlation_id.clone(),
+            old_status,
+            new_status,
+            cp_id: row.get("cp_id"),
+            pid: row.get("pid"),
+            time: event.time,
+            outcome: event.outcome.clone(),
+            first_event_date: row.get("first_event_date"),
+            old_event_date: row.get("old_event_date"),
+            stage: None,
+        })
+    }
+
+    /// The report of a failed `stage` of `event`, which keeps the status of
+    /// the SIP in the `sipin_sips` row, with columns `status`,
+    /// `last_event_date`, `first_event_date`, `cp_id` and `pid`.
+    pub fn from_failed_stage(row: &Row, event: &CloudEvent, stage: &str) -> StatusChange {
+        let status: String = row.get("status");
+        StatusChange {
+            correlation_id: event.correlation_id.clone(),
+            old_status: Some(status.clone()),
+            new_status: status,
+            cp_id: row.get("cp_id"),
+            pid: row.get("pid"),
+            time: event.time,
+            outcome: event.outcome.clone(),
+            first_event_date: row.get("first_event_date"),
+            old_event_date: row.get("last_event_date"),
+            stage: Some(String::from(stage)),
+        }
+    }
+
+    /// The CloudEvent announcing this change, with the time spent in the old
+    /// status and since the first event, in seconds.
+    pub fn to_cloud_event(&self) -> CloudEvent {
+        let seconds = |since: DateTime<Utc>| (self.time - since).num_milliseconds() as f64 / 1000.0;
+        CloudEvent {
+            type_field: String::from(STATUS_CHANGED_EVENT_TYPE),
+            source: String::from("pulsar2db"),
+            correlation_id: self.correlation_id.clone(),
+            content_type: String::from("application/json"),
+            time: self.time,
+            datacontenttype: String::from("application/json"),
+            outcome: self.outcome.clone(),
+            specversion: String::from("1.0"),
+            id: format!("{}:{}:{}", self.correlation_id, self.new_status, self.time.timestamp_millis()),
+            subject: self.correlation_id.clone(),
+            data: json!({
+                "previous_status": self.old_status,
+                "status": self.new_status,
+                "cp_id": self.cp_id,
+                "pid": self.pid,
+                "first_event_date": self.first_event_date,
+                "seconds_in_previous_status": self.old_event_date.map(seconds),
+                "seconds_since_first_event": seconds(self.first_event_date),
+            }),
+        }
+    }
+}
+
+/// Where a status change goes besides `sipin_sips`: a Postgres notification
+/// on `notify_channel` (if set), the outbox (if there is an `output_topic`),
+/// the webhook of the CP and, once committed, the listeners of `broadcast`.
+/// With a `notify_channel`, the changes are broadcast by `listen` instead, so
+/// the listeners get the changes of every instance.
+pub struct ChangeSinks {
+    pub broadcast: broadcast::Sender<StatusChange>,
+    pub notify_channel: String,
+    pub output_topic: String,
+    pub outbox_wake: Arc<Notify>,
+    pub webhooks: Arc<Webhooks>,
+    pub webhook_wake: Arc<Notify>,
+}
+
+impl ChangeSinks {
+    /// Send the notification, and add the change to the outbox and the
+    /// webhook deliveries, as part of the transaction that made it.
+    pub async fn record(&self, transaction: &Transaction<'_>, change: &StatusChange) -> Result<(), tokio_postgres::Error> {
+        if !self.notify_channel.is_empty() {
+            let payload = serde_json::to_string(change).unwrap_or_default();
+            transaction.execute("SELECT pg_notify($1, $2)", &[&self.notify_channel, &payload]).await?;
+        }
+        if !self.output_topic.is_empty() {
+            outbox::enqueue(transaction, &self.output_topic, &change.to_cloud_event()).await?;
+        }
+        if let Some(webhook) = self.webhooks.for_change(change) {
+            webhooks::enqueue(transaction, webhook, change).await?;
+        }
+        Ok(())
+    }
+
+    /// Add the webhook delivery for a failed stage, see
+    /// `StatusChange::from_failed_stage`, as part of the transaction that
+    /// recorded the stage. Returns true if the CP has a webhook, which is to
+    /// be woken once committed.
+    pub async fn record_failed_stage(&self, transaction: &Transaction<'_>, change: &StatusChange) -> Result<bool, tokio_postgres::Error> {
+        match self.webhooks.for_change(change) {
+            Some(webhook) => webhooks::enqueue(transaction, webhook, change).await.map(|_| true),
+            None => Ok(false),
+        }
+    }
+
+    /// Wake the outbox relay and the webhook sender for a change recorded
+    /// with `record`, and broadcast it, once the transaction was committed.
+    pub fn committed(&self, change: StatusChange) {
+        if self.webhooks.for_change(&change).is_some() {
+            self.webhook_wake.notify_one();
+        }
+        if !self.output_topic.is_empty() {
+            self.outbox_wake.notify_one();
+        }
+        // Nobody listening is fine.
+        if self.notify_channel.is_empty() {
+            let _ = self.broadcast.send(change);
+        }
+    }
+}
+
+/// Connect to Postgres, `LISTEN` on `channel` and broadcast the status changes
+/// notified on it, by any instance, until the connection is lost. Returns an
+/// error if listening didn't get started.
+pub async fn listen(connection_string: &str, channel: &str, broadcast: &broadcast::Sender<StatusChange>) -> Result<(), anyhow::Error> {
+    let (client, mut connection) = tokio_postgres::connect(connection_string, NoTls).await?;
+    let broadcast = broadcast.clone();
+    // The notifications come in on the connection, which also carries the
+    // LISTEN itself.
+    let notifications = tokio::spawn(async move {
+        let mut messages = futures::stream::poll_fn(move |cx| connection.poll_message(cx));
+        while let Some(message) = messages.next().await {
+            match message {
+                Ok(AsyncMessage::Notification(notification)) => match serde_json::from_str(notification.payload()) {
+                    // Nobody listening is fine.
+                    Ok(change) => {
+                        let _ = broadcast.send(change);
+                    },
+                    Err(e) => log::warn!("Ignoring notification {:?}: {:?}", notification.payload(), e),
+                },
+                Ok(_) => (),
+                Err(e) => {
+                    log::error!("Lost the connection listening for status changes: {:?}", e);
+                    break;
+                },
+            }
+        }
+    });
+    client.batch_execute(&format!("LISTEN \"{}\"", channel.replace('"', "\"\""))).await?;
+    log::info!("Listening for status changes on {}", channel);
+    notifications.await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::change;
+    #[test]
+    fn to_cloud_event_durations() {
+        let change = StatusChange {
+            old_status: Some(String::from("BAG_TRANSFERRED_TO_SIPIN")),
+            time: "2024-05-20T10:01:30Z".parse().unwrap(),
+            first_event_date: "2024-05-20T09:00:00Z".parse().unwrap(),
+            old_event_date: Some("2024-05-20T10:00:00Z".parse().unwrap()),
+            ..change("BAG_UNZIPPED", "success")
+        };
+        let event = change.to_cloud_event();
+        assert_eq!(event.type_field, STATUS_CHANGED_EVENT_TYPE);
+        assert_eq!(event.correlation_id, "abc");
+        assert_eq!(event.data["previous_status"], "BAG_TRANSFERRED_TO_SIPIN");
+        assert_eq!(event.data["status"], "BAG_UNZIPPED");
+        assert_eq!(event.data["seconds_in_previous_status"], 90.0);
+        assert_eq!(event.data["seconds_since_first_event"], 3690.0);
+    }
+    #[tokio::test]
+    #[ignore = "needs PULSAR2DB_TEST_POSTGRES"]
+    async fn listen_broadcasts_notifications() {
+        let client = crate::testing::database("listen_broadcasts_notifications", crate::testing::DDL).await;
+        let (sender, mut receiver) = broadcast::channel(16);
+        let connection_string = crate::testing::connection_string("listen_broadcasts_notifications");
+        tokio::spawn(async move { listen(&connection_string, "sip changes", &sender).await });
+        let change = change("SIP_CREATED", "success");
+        let payload = serde_json::to_string(&change).unwrap();
+        // Notifications sent before the LISTEN are missed.
+        for _ in 0..50 {
+            client.execute("SELECT pg_notify('sip changes', $1)", &[&payload]).await.unwrap();
+            if let Ok(received) = tokio::time::timeout(std::time::Duration::from_millis(100), receiver.recv()).await {
+                assert_eq!(received.unwrap(), change);
+                return;
+            }
+        }
+        panic!("no status change received");
+    }
+}
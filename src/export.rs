@@ -0,0 +1,441 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use arrow_array::builder::{ArrayBuilder, BooleanBuilder, Int64Builder, StringBuilder, TimestampMicrosecondBuilder};
+use arrow_array::RecordBatch;
+use arrow_schema::{DataType, Field, Schema, TimeUnit};
+use chrono::{DateTime, NaiveDate, NaiveTime, TimeZone, Utc};
+use futures::{pin_mut, TryStreamExt};
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
+use tokio_postgres::{types::ToSql, Client, Row};
+
+// Rows per record batch written to a Parquet file.
+const BATCH_SIZE: usize = 10000;
+
+/// The Postgres type of a column, as in `ddl.sql`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    /// text, varchar and bpchar
+    Text,
+    /// int8
+    Int64,
+    /// bool
+    Bool,
+    /// timestamptz, with microsecond precision like Postgres
+    Timestamp,
+}
+
+/// A column of an exported table: name, type and whether it is nullable.
+pub type Column = (&'static str, Kind, bool);
+
+/// A table exported to Parquet, partitioned by the day of `date_column`.
+#[derive(Debug)]
+pub struct Table {
+    pub name: &'static str,
+    pub columns: &'static [Column],
+    pub date_column: &'static str,
+}
+
+/// `sipin_sips`, without the internal `row_id`, partitioned by the day of
+/// the first event.
+pub const SIPS: Table = Table {
+    name: "sipin_sips",
+    columns: &[
+        ("correlation_id", Kind::Text, false),
+        ("bag_name", Kind::Text, true),
+        ("cp_id", Kind::Text, true),
+        ("local_id", Kind::Text, true),
+        ("md5_hash_sip", Kind::Text, true),
+        ("md5_hash_essence_manifest", Kind::Text, true),
+        ("md5_hash_essence_sidecar", Kind::Text, true),
+        ("checksum_mismatch", Kind::Bool, true),
+        ("essence_filename", Kind::Text, true),
+        ("essence_filesize", Kind::Int64, true),
+        ("ingest_host", Kind::Text, true),
+        ("ingest_bucket", Kind::Text, true),
+        ("ingest_path_or_key", Kind::Text, true),
+        ("bag_filesize", Kind::Int64, true),
+        ("pid", Kind::Text, true),
+        ("mh_record_id", Kind::Text, true),
+        ("sip_profile", Kind::Text, true),
+        ("first_event_date", Kind::Timestamp, false),
+        ("last_event_type", Kind::Text, false),
+        ("last_event_date", Kind::Timestamp, false),
+        ("status", Kind::Text, false),
+        ("stalled", Kind::Bool, false),
+        ("resolution", Kind::Text, true),
+    ],
+    date_column: "first_event_date",
+};
+
+/// `sipin_sip_stages`, partitioned by the day of the event. It holds the
+/// last event per stage of a SIP, not every raw event, which isn't stored.
+pub const STAGES: Table = Table {
+    name: "sipin_sip_stages",
+    columns: &[
+        ("correlation_id", Kind::Text, false),
+        ("stage", Kind::Text, false),
+        ("event_type", Kind::Text, false),
+        ("event_date", Kind::Timestamp, false),
+        ("outcome", Kind::Text, true),
+    ],
+    date_column: "event_date",
+};
+
+impl Table {
+    /// The Arrow schema of the table, the same for every export.
+    pub fn schema(&self) -> Schema {
+        let fields: Vec<Field> = self.columns.iter().map(|(name, kind, nullable)| {
+            let data_type = match kind {
+                Kind::Text => DataType::Utf8,
+                Kind::Int64 => DataType::Int64,
+                Kind::Bool => DataType::Boolean,
+                Kind::Timestamp => DataType::Timestamp(TimeUnit::Microsecond, Some("UTC".into())),
+            };
+            Field::new(*name, data_type, *nullable)
+        }).collect();
+        Schema::new(fields)
+    }
+
+    /// The file holding the rows of a day, in a Hive-style partition
+    /// directory, eg. `<output>/sipin_sips/date=2024-05-20/data.parquet`.
+    pub fn partition_path(&self, output: &Path, date: NaiveDate) -> PathBuf {
+        output.join(self.name).join(format!("date={}", date.format("%Y-%m-%d"))).join("data.parquet")
+    }
+
+    /// The rows as a record batch.
+    pub fn record_batch(&self, schema: &Arc<Schema>, rows: &[Row]) -> Result<RecordBatch, anyhow::Error> {
+        let mut columns = Vec::with_capacity(self.columns.len());
+        for (i, (_, kind, _)) in self.columns.iter().enumerate() {
+            let mut builder: Box<dyn ArrayBuilder> = match kind {
+                Kind::Text => {
+                    let mut builder = StringBuilder::new();
+                    rows.iter().try_for_each(|row| row.try_get::<_, Option<&str>>(i).map(|value| builder.append_option(value)))?;
+                    Box::new(builder)
+                },
+                Kind::Int64 => {
+                    let mut builder = Int64Builder::new();
+                    rows.iter().try_for_each(|row| row.try_get::<_, Option<i64>>(i).map(|value| builder.append_option(value)))?;
+                    Box::new(builder)
+                },
+                Kind::Bool => {
+                    let mut builder = BooleanBuilder::new();
+                    rows.iter().try_for_each(|row| row.try_get::<_, Option<bool>>(i).map(|value| builder.append_option(value)))?;
+                    Box::new(builder)
+                },
+                Kind::Timestamp => {
+                    let mut builder = TimestampMicrosecondBuilder::new().with_timezone("UTC");
+                    rows.iter().try_for_each(|row| {
+                        row.try_get::<_, Option<DateTime<Utc>>>(i).map(|value| builder.append_option(value.map(|time| time.timestamp_micros())))
+                    })?;
+                    Box::new(builder)
+                },
+            };
+            columns.push(builder.finish());
+        }
+        Ok(RecordBatch::try_new(schema.clone(), columns)?)
+    }
+}
+
+/// The arguments of the `export` subcommand.
+#[derive(clap::Args, Debug)]
+pub struct ExportArgs {
+    /// Directory to write the Parquet files to
+    #[arg(long, short)]
+    pub output: PathBuf,
+    /// First day to export (YYYY-MM-DD, UTC)
+    #[arg(long)]
+    pub from: Option<NaiveDate>,
+    /// First day to leave out (YYYY-MM-DD, UTC)
+    #[arg(long)]
+    pub to: Option<NaiveDate>,
+    /// Also export sipin_sip_stages: the last event per stage of every SIP
+    #[arg(long)]
+    pub events: bool,
+}
+
+/// A Parquet file being written. It is written next to its final path and
+/// only moved there once complete, so readers never see half a file. The
+/// temporary file is removed if the partition is dropped before that.
+struct Partition {
+    date: NaiveDate,
+    path: PathBuf,
+    temporary: PathBuf,
+    writer: Option<ArrowWriter<fs::File>>,
+}
+
+impl Partition {
+    fn create(path: PathBuf, date: NaiveDate, schema: &Arc<Schema>) -> Result<Partition, anyhow::Error> {
+        if let Some(directory) = path.parent() {
+            fs::create_dir_all(directory)?;
+        }
+        let temporary = temporary_path(&path);
+        let properties = WriterProperties::builder().set_compression(Compression::SNAPPY).build();
+        let writer = ArrowWriter::try_new(fs::File::create(&temporary)?, schema.clone(), Some(properties))?;
+        Ok(Partition { date, path, temporary, writer: Some(writer) })
+    }
+
+    fn write(&mut self, batch: &RecordBatch) -> Result<(), anyhow::Error> {
+        self.writer.as_mut().expect("the partition is not finished").write(batch)?;
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<(), anyhow::Error> {
+        if let Some(writer) = self.writer.take() {
+            writer.close()?;
+            fs::rename(&self.temporary, &self.path)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Partition {
+    fn drop(&mut self) {
+        if self.writer.is_some() {
+            let _ = fs::remove_file(&self.temporary);
+        }
+    }
+}
+
+fn temporary_path(path: &Path) -> PathBuf {
+    path.with_extension("parquet.tmp")
+}
+
+/// Remove the files of the days in `from..to` that weren't `written`, since
+/// those days have no rows anymore, and temporary files left behind by
+/// exports that were killed. Returns the number of files removed.
+fn remove_stale(
+    table: &Table,
+    output: &Path,
+    from: Option<NaiveDate>,
+    to: Option<NaiveDate>,
+    written: &HashSet<NaiveDate>,
+) -> Result<usize, anyhow::Error> {
+    let directory = output.join(table.name);
+    if !directory.exists() {
+        return Ok(0);
+    }
+    let mut removed = 0;
+    for entry in fs::read_dir(&directory)? {
+        let entry = entry?;
+        let date = entry.file_name().to_str()
+            .and_then(|name| name.strip_prefix("date="))
+            .and_then(|date| NaiveDate::parse_from_str(date, "%Y-%m-%d").ok());
+        let Some(date) = date else { continue };
+        if from.is_some_and(|from| date < from) || to.is_some_and(|to| date >= to) {
+            continue;
+        }
+        let path = table.partition_path(output, date);
+        let temporary = temporary_path(&path);
+        if temporary.exists() {
+            fs::remove_file(&temporary)?;
+            removed += 1;
+        }
+        if !written.contains(&date) && path.exists() {
+            log::info!("Removing {}: the day has no rows anymore", path.display());
+            fs::remove_file(&path)?;
+            let _ = fs::remove_dir(entry.path());
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+/// Write the rows of a table to Parquet, one file per day. Rows are streamed
+/// in order of their day, so only a batch is held in memory. Files of days in
+/// the range without rows are removed. Returns the number of rows and files
+/// written.
+pub async fn export_table(
+    client: &Client,
+    table: &Table,
+    output: &Path,
+    from_date: Option<NaiveDate>,
+    to_date: Option<NaiveDate>,
+) -> Result<(usize, usize), anyhow::Error> {
+    let from = from_date.map(|date| Utc.from_utc_datetime(&date.and_time(NaiveTime::MIN)));
+    let to = to_date.map(|date| Utc.from_utc_datetime(&date.and_time(NaiveTime::MIN)));
+    let mut conditions: Vec<String> = Vec::new();
+    let mut params: Vec<&(dyn ToSql + Sync)> = Vec::new();
+    if let Some(from) = &from {
+        params.push(from);
+        conditions.push(format!("{} >= ${}", table.date_column, params.len()));
+    }
+    if let Some(to) = &to {
+        params.push(to);
+        conditions.push(format!("{} < ${}", table.date_column, params.len()));
+    }
+    let filter = match conditions.is_empty() {
+        true => String::new(),
+        false => format!("WHERE {}", conditions.join(" AND ")),
+    };
+    let columns: Vec<&str> = table.columns.iter().map(|(name, _, _)| *name).collect();
+    let statement = format!(
+        "SELECT {} FROM {} {} ORDER BY {}",
+        columns.join(", "), table.name, filter, table.date_column,
+    );
+
+    let schema = Arc::new(table.schema());
+    let date_index = columns.iter().position(|name| *name == table.date_column).expect("the date column is exported");
+    let stream = client.query_raw(statement.as_str(), params).await?;
+    pin_mut!(stream);
+    let mut partition: Option<Partition> = None;
+    let mut batch: Vec<Row> = Vec::with_capacity(BATCH_SIZE);
+    let mut written = HashSet::new();
+    let mut rows = 0;
+    while let Some(row) = stream.try_next().await? {
+        let date = row.get::<_, DateTime<Utc>>(date_index).naive_utc().date();
+        if partition.as_ref().map(|partition| partition.date) != Some(date) {
+            if let Some(mut partition) = partition.take() {
+                partition.write(&table.record_batch(&schema, &batch)?)?;
+                partition.finish()?;
+                batch.clear();
+            }
+            partition = Some(Partition::create(table.partition_path(output, date), date, &schema)?);
+            written.insert(date);
+        }
+        batch.push(row);
+        rows += 1;
+        if batch.len() >= BATCH_SIZE {
+            if let Some(partition) = partition.as_mut() {
+                partition.write(&table.record_batch(&schema, &batch)?)?;
+            }
+            batch.clear();
+        }
+    }
+    if let Some(mut partition) = partition {
+        partition.write(&table.record_batch(&schema, &batch)?)?;
+        partition.finish()?;
+    }
+    remove_stale(table, output, from_date, to_date, &written)?;
+    Ok((rows, written.len()))
+}
+
+/// Run the `export` subcommand.
+pub async fn run(client: &Client, args: &ExportArgs) -> Result<(), anyhow::Error> {
+    let mut tables = vec![&SIPS];
+    if args.events {
+        tables.push(&STAGES);
+    }
+    for table in tables {
+        let (rows, files) = export_table(client, table, &args.output, args.from, args.to).await?;
+        log::info!("Exported {} rows of {} to {} files", rows, table.name, files);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow_array::{Array, StringArray, TimestampMicrosecondArray};
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    /// The columns of a table in a DDL file, with the kind of their type,
+    /// leaving out the internal `row_id`.
+    fn ddl_columns(ddl: &str, table: &str) -> Vec<(String, Kind, bool)> {
+        let start = ddl.find(&format!("CREATE TABLE public.{} (", table)).unwrap();
+        ddl[start..].lines().skip(1)
+            .take_while(|line| !line.starts_with(')'))
+            .map(str::trim)
+            .filter(|line| !line.starts_with("CONSTRAINT") && !line.starts_with("row_id"))
+            .map(|line| {
+                let definition = line.split(" -- ").next().unwrap();
+                let mut words = definition.split_whitespace();
+                let name = words.next().unwrap().to_string();
+                let kind = match words.next().unwrap().split('(').next().unwrap() {
+                    "text" | "varchar" | "bpchar" => Kind::Text,
+                    "int8" => Kind::Int64,
+                    "bool" => Kind::Bool,
+                    "timestamptz" => Kind::Timestamp,
+                    other => panic!("unexpected type {} of {}", other, name),
+                };
+                (name, kind, !definition.contains("NOT NULL"))
+            })
+            .collect()
+    }
+
+    fn columns(table: &Table) -> Vec<(String, Kind, bool)> {
+        table.columns.iter().map(|(name, kind, nullable)| (name.to_string(), *kind, *nullable)).collect()
+    }
+
+    #[test]
+    fn columns_follow_ddl() {
+        assert_eq!(columns(&SIPS), ddl_columns(include_str!("../ddl.sql"), "sipin_sips"));
+        assert_eq!(columns(&SIPS), ddl_columns(include_str!("../ddl_partitioned.sql"), "sipin_sips"));
+        assert_eq!(columns(&STAGES), ddl_columns(include_str!("../ddl.sql"), "sipin_sip_stages"));
+    }
+    #[test]
+    fn schema_types() {
+        let schema = SIPS.schema();
+        let field = schema.field_with_name("first_event_date").unwrap();
+        assert_eq!(field.data_type(), &DataType::Timestamp(TimeUnit::Microsecond, Some("UTC".into())));
+        assert!(!field.is_nullable());
+        assert_eq!(schema.field_with_name("bag_filesize").unwrap().data_type(), &DataType::Int64);
+        assert!(STAGES.columns.iter().any(|(name, _, _)| *name == STAGES.date_column));
+    }
+    #[test]
+    fn partition_path_per_day() {
+        let path = SIPS.partition_path(Path::new("/data"), NaiveDate::from_ymd_opt(2024, 5, 20).unwrap());
+        assert_eq!(path, PathBuf::from("/data/sipin_sips/date=2024-05-20/data.parquet"));
+    }
+    #[test]
+    fn partition_written_on_finish() {
+        let directory = std::env::temp_dir().join(format!("pulsar2db-export-{}", std::process::id()));
+        let schema = Arc::new(STAGES.schema());
+        let path = STAGES.partition_path(&directory, NaiveDate::from_ymd_opt(2024, 5, 20).unwrap());
+        let mut partition = Partition::create(path.clone(), NaiveDate::from_ymd_opt(2024, 5, 20).unwrap(), &schema).unwrap();
+        let batch = RecordBatch::try_new(schema.clone(), vec![
+            Arc::new(StringArray::from(vec!["abc"])),
+            Arc::new(StringArray::from(vec!["unzip"])),
+            Arc::new(StringArray::from(vec!["be.meemoo.sipin.bag.unzip"])),
+            Arc::new(TimestampMicrosecondArray::from(vec![Utc::now().timestamp_micros()]).with_timezone("UTC")),
+            Arc::new(StringArray::from(vec![None::<&str>])),
+        ]).unwrap();
+        partition.write(&batch).unwrap();
+        assert!(!path.exists());
+        partition.finish().unwrap();
+
+        let reader = ParquetRecordBatchReaderBuilder::try_new(fs::File::open(&path).unwrap()).unwrap();
+        assert_eq!(reader.schema().as_ref(), schema.as_ref());
+        let batches: Vec<RecordBatch> = reader.build().unwrap().collect::<Result<_, _>>().unwrap();
+        assert_eq!(batches[0].num_rows(), 1);
+        assert!(batches[0].column(4).is_null(0));
+        fs::remove_dir_all(&directory).unwrap();
+    }
+    #[test]
+    fn partition_dropped_unfinished() {
+        let directory = std::env::temp_dir().join(format!("pulsar2db-export-dropped-{}", std::process::id()));
+        let date = NaiveDate::from_ymd_opt(2024, 5, 20).unwrap();
+        let path = STAGES.partition_path(&directory, date);
+        let partition = Partition::create(path.clone(), date, &Arc::new(STAGES.schema())).unwrap();
+        assert!(temporary_path(&path).exists());
+        drop(partition);
+        assert!(!temporary_path(&path).exists());
+        assert!(!path.exists());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+    #[test]
+    fn remove_stale_days_in_range() {
+        let directory = std::env::temp_dir().join(format!("pulsar2db-export-stale-{}", std::process::id()));
+        let day = |day| NaiveDate::from_ymd_opt(2024, 5, day).unwrap();
+        for date in [day(1), day(2), day(3), day(10)] {
+            let path = SIPS.partition_path(&directory, date);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, b"").unwrap();
+        }
+        fs::write(temporary_path(&SIPS.partition_path(&directory, day(1))), b"").unwrap();
+        let written = HashSet::from([day(1), day(3)]);
+        assert_eq!(remove_stale(&SIPS, &directory, Some(day(1)), Some(day(5)), &written).unwrap(), 2);
+        assert!(SIPS.partition_path(&directory, day(1)).exists());
+        assert!(!temporary_path(&SIPS.partition_path(&directory, day(1))).exists());
+        assert!(!SIPS.partition_path(&directory, day(2)).exists());
+        assert!(SIPS.partition_path(&directory, day(3)).exists());
+        // Outside of the range
+        assert!(SIPS.partition_path(&directory, day(10)).exists());
+        assert_eq!(remove_stale(&SIPS, &directory, None, None, &written).unwrap(), 1);
+        assert!(!SIPS.partition_path(&directory, day(10)).exists());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -1,7 +1,28 @@
 use std::str;
+use std::sync::Arc;
 use serde::{Serialize, Deserialize};
 use chrono::{DateTime, Utc};
-use pulsar::{message::Payload, DeserializeMessage};
+use pulsar::{
+    consumer::InitialPosition, message::Payload, message::proto::command_subscribe::SubType,
+    producer, DeserializeMessage, SerializeMessage,
+};
+use tokio_postgres::{Client, NoTls};
+
+pub mod admin;
+pub mod api;
+pub mod changes;
+pub mod export;
+pub mod metrics;
+pub mod outbox;
+pub mod partitions;
+pub mod projection;
+pub mod report;
+pub mod s3;
+pub mod schema;
+pub mod stalled;
+#[doc(hidden)]
+pub mod testing;
+pub mod webhooks;
 
 #[derive(Deserialize, Debug)]
 pub struct Config {
@@ -14,11 +35,47 @@ pub struct Config {
     pub pulsar_host: String,
     #[serde(default="default_port")]
     pub pulsar_port: String,
+    // Comma-separated lists of Pulsar service URLs. When no service URLs are
+    // set, the URL is built from the host and port.
+    #[serde(default)]
+    pub pulsar_service_urls: String,
+    #[serde(default)]
+    pub pulsar_failover_urls: String,
+    #[serde(default="default_reconnect_min_backoff_ms")]
+    pub pulsar_reconnect_min_backoff_ms: u64,
+    #[serde(default="default_reconnect_max_backoff")]
+    pub pulsar_reconnect_max_backoff: u64,
     pub pulsar_topics: String,
     #[serde(default="default_consumer_name")]
     pub pulsar_consumer_name: String,
     #[serde(default="default_subscription_name")]
     pub pulsar_subscription_name: String,
+    #[serde(default="default_subscription_type")]
+    pub pulsar_subscription_type: String,
+    #[serde(default="default_partition_check_interval")]
+    pub pulsar_partition_check_interval: u64,
+    #[serde(default="default_initial_position")]
+    pub pulsar_initial_position: String,
+    #[serde(default="default_receiver_queue_size")]
+    pub pulsar_receiver_queue_size: u32,
+    // Seconds after which unacknowledged messages are redelivered. 0 disables.
+    #[serde(default)]
+    pub pulsar_ack_timeout: u64,
+    // Redeliveries before a message goes to the dead letter topic. 0 disables.
+    #[serde(default="default_max_redeliveries")]
+    pub pulsar_max_redeliveries: usize,
+    #[serde(default="default_dead_letter_topic")]
+    pub pulsar_dead_letter_topic: String,
+    // Topic on which status changes are published. Empty disables.
+    #[serde(default)]
+    pub pulsar_output_topic: String,
+    #[serde(default="default_outbox_poll_interval_ms")]
+    pub outbox_poll_interval_ms: u64,
+    #[serde(default="default_outbox_batch_size")]
+    pub outbox_batch_size: i64,
+    // Seconds published events are kept in the outbox
+    #[serde(default="default_outbox_retention")]
+    pub outbox_retention: u64,
     // Postgres
     #[serde(default="default_user_pass")]
     pub postgres_user: String,
@@ -28,6 +85,82 @@ pub struct Config {
     pub postgres_host: String,
     #[serde(default="default_database")]
     pub postgres_database: String,
+    // Create and update the reporting views at startup
+    #[serde(default="default_create_views")]
+    pub postgres_create_views: bool,
+    // Channel on which status changes are sent with pg_notify. Empty disables.
+    #[serde(default)]
+    pub postgres_notify_channel: String,
+    // Monthly partitioning of sipin_sips, see `ddl_partitioned.sql`
+    #[serde(default)]
+    pub postgres_partitioning: bool,
+    #[serde(default="default_partitions_ahead")]
+    pub postgres_partitions_ahead: u32,
+    #[serde(default="default_partition_maintenance_interval")]
+    pub postgres_partition_maintenance_interval: u64,
+    // Months after which partitions of completed SIPs are detached. 0 disables.
+    #[serde(default)]
+    pub postgres_retention_months: u32,
+    #[serde(default="default_archive_schema")]
+    pub postgres_archive_schema: String,
+    // Number of lanes handling events concurrently
+    #[serde(default="default_workers")]
+    pub workers: usize,
+    // Times a lane retries an event before handing it back to Pulsar
+    #[serde(default="default_lane_retries")]
+    pub lane_retries: u32,
+    // Maximum seconds between the retries of an event by a lane
+    #[serde(default="default_lane_retry_max_backoff")]
+    pub lane_retry_max_backoff: u64,
+    // Regexes selecting the S3 objects that become SIPs. Empty for none.
+    #[serde(default)]
+    pub s3_bucket_include: String,
+    #[serde(default)]
+    pub s3_bucket_exclude: String,
+    #[serde(default)]
+    pub s3_key_include: String,
+    #[serde(default)]
+    pub s3_key_exclude: String,
+    // Mapping of S3 buckets and key prefixes to cp_ids, from a JSON file or
+    // the sipin_cp_mappings table.
+    #[serde(default)]
+    pub cp_mapping_file: String,
+    #[serde(default)]
+    pub cp_mapping_table: bool,
+    #[serde(default="default_cp_mapping_refresh_interval")]
+    pub cp_mapping_refresh_interval: u64,
+    // JSON file with additional projections. Empty for none.
+    #[serde(default)]
+    pub projections_file: String,
+    // Metrics
+    #[serde(default="default_metrics_port")]
+    pub metrics_port: u16,
+    #[serde(default="default_lag_log_interval")]
+    pub lag_log_interval: u64,
+    // JSON file with the webhooks per cp_id. Empty for none.
+    #[serde(default)]
+    pub webhooks_file: String,
+    #[serde(default="default_webhook_max_attempts")]
+    pub webhook_max_attempts: u32,
+    #[serde(default="default_webhook_backoff")]
+    pub webhook_backoff: u64,
+    #[serde(default="default_webhook_timeout")]
+    pub webhook_timeout: u64,
+    #[serde(default="default_webhook_poll_interval")]
+    pub webhook_poll_interval: u64,
+    // Port for the JSON API. 0 disables.
+    #[serde(default)]
+    pub api_port: u16,
+    // Bearer token for the admin endpoints of the API. Empty disables them.
+    #[serde(default)]
+    pub admin_token: String,
+    // Stalled SIP detection
+    #[serde(default="default_stalled_check_interval")]
+    pub stalled_check_interval: u64,
+    #[serde(default="default_stalled_timeout")]
+    pub stalled_timeout: u64,
+    #[serde(default)]
+    pub stalled_timeouts: String,
 }
 
 fn default_user_pass() -> String  {
@@ -42,10 +175,50 @@ fn default_port() -> String  {
   String::from("5672")
 }
 
+fn default_reconnect_min_backoff_ms() -> u64  {
+  100
+}
+
+fn default_reconnect_max_backoff() -> u64  {
+  30
+}
+
 fn default_database() -> String  {
   String::from("postgres")
 }
 
+fn default_create_views() -> bool  {
+  true
+}
+
+fn default_partitions_ahead() -> u32  {
+  3
+}
+
+fn default_partition_maintenance_interval() -> u64  {
+  86400
+}
+
+fn default_archive_schema() -> String  {
+  String::from("archive")
+}
+
+fn default_workers() -> usize  {
+  4
+}
+
+fn default_lane_retries() -> u32  {
+  3
+}
+
+fn default_lane_retry_max_backoff() -> u64  {
+  60
+}
+
+fn default_cp_mapping_refresh_interval() -> u64  {
+  300
+}
+
 fn default_consumer_name() -> String  {
   String::from("pulsar2db")
 }
@@ -54,6 +227,74 @@ fn default_subscription_name() -> String  {
   String::from("pulsar2db_subscription")
 }
 
+fn default_subscription_type() -> String  {
+  String::from("Exclusive")
+}
+
+fn default_partition_check_interval() -> u64  {
+  300
+}
+
+fn default_initial_position() -> String  {
+  String::from("Latest")
+}
+
+fn default_receiver_queue_size() -> u32  {
+  1000
+}
+
+fn default_max_redeliveries() -> usize  {
+  5
+}
+
+fn default_dead_letter_topic() -> String  {
+  String::from("public/sipin/pulsar2db-DLQ")
+}
+
+fn default_outbox_poll_interval_ms() -> u64  {
+  1000
+}
+
+fn default_outbox_batch_size() -> i64  {
+  100
+}
+
+fn default_outbox_retention() -> u64  {
+  86400
+}
+
+fn default_webhook_max_attempts() -> u32  {
+  8
+}
+
+fn default_webhook_backoff() -> u64  {
+  30
+}
+
+fn default_webhook_timeout() -> u64  {
+  10
+}
+
+fn default_webhook_poll_interval() -> u64  {
+  10
+}
+
+fn default_metrics_port() -> u16  {
+  9090
+}
+
+fn default_lag_log_interval() -> u64  {
+  60
+}
+
+fn default_stalled_check_interval() -> u64  {
+  300
+}
+
+fn default_stalled_timeout() -> u64  {
+  86400
+}
+
 // TODO: These 2 conn string fn's can become methods on their respective configs
 pub fn format_pulsar_connection_string(config: &Config) -> String {
     format!("pulsar://{}:{}",
@@ -62,6 +303,25 @@ pub fn format_pulsar_connection_string(config: &Config) -> String {
     )
 }
 
+/// Returns the Pulsar service URLs to connect to, in order of preference:
+/// the configured service URLs (or the URL built from host and port if there
+/// are none), followed by the URLs of the failover cluster.
+pub fn pulsar_service_urls(config: &Config) -> Vec<String> {
+    let split = |urls: &str| -> Vec<String> {
+        urls.split(',')
+            .map(str::trim)
+            .filter(|url| !url.is_empty())
+            .map(String::from)
+            .collect()
+    };
+    let mut urls = split(&config.pulsar_service_urls);
+    if urls.is_empty() {
+        urls.push(format_pulsar_connection_string(config));
+    }
+    urls.extend(split(&config.pulsar_failover_urls));
+    urls
+}
+
 pub fn format_postgres_connection_string(config: &Config) -> String {
     format!("postgresql://{}:{}@{}/{}",
         config.postgres_user,
@@ -71,7 +331,87 @@ pub fn format_postgres_connection_string(config: &Config) -> String {
     )
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Connect to Postgres and spawn the connection object, which performs the
+/// actual communication with the database, off to run on its own.
+pub async fn connect_postgres(config: &Config) -> Result<Client, tokio_postgres::Error> {
+    connect(&format_postgres_connection_string(config)).await
+}
+
+async fn connect(connection_string: &str) -> Result<Client, tokio_postgres::Error> {
+    let (client, connection) = tokio_postgres::connect(connection_string, NoTls).await?;
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            eprintln!("connection error: {}", e);
+        }
+    });
+    Ok(client)
+}
+
+/// A Postgres connection shared by the tasks that run single statements on
+/// it, such as the API and the stalled SIP detection. Unlike a plain `Client`,
+/// it is opened again once lost, eg. after a restart of Postgres.
+pub struct SharedClient {
+    connection_string: String,
+    client: tokio::sync::Mutex<Arc<Client>>,
+}
+
+impl SharedClient {
+    pub fn new(connection_string: String, client: Client) -> SharedClient {
+        SharedClient { connection_string, client: tokio::sync::Mutex::new(Arc::new(client)) }
+    }
+
+    /// The connection, opened again first if it was lost.
+    pub async fn get(&self) -> Result<Arc<Client>, tokio_postgres::Error> {
+        let mut client = self.client.lock().await;
+        if client.is_closed() {
+            log::warn!("Lost the shared connection to Postgres, reconnecting");
+            *client = Arc::new(self.connect().await?);
+        }
+        Ok(client.clone())
+    }
+
+    /// A new connection of its own, with the same parameters.
+    pub async fn connect(&self) -> Result<Client, tokio_postgres::Error> {
+        connect(&self.connection_string).await
+    }
+}
+
+/// Parses a Pulsar subscription type: `Exclusive`, `Shared`, `Failover` or
+/// `Key_Shared` (case-insensitive).
+pub fn parse_subscription_type(subscription_type: &str) -> Result<SubType, anyhow::Error> {
+    match subscription_type.to_lowercase().replace('-', "_").as_str() {
+        "exclusive" => Ok(SubType::Exclusive),
+        "shared" => Ok(SubType::Shared),
+        "failover" => Ok(SubType::Failover),
+        "key_shared" | "keyshared" => Ok(SubType::KeyShared),
+        _ => Err(anyhow::anyhow!("unknown subscription type: {}", subscription_type)),
+    }
+}
+
+/// Parses the initial position of a new subscription: `Earliest` or `Latest`
+/// (case-insensitive).
+pub fn parse_initial_position(initial_position: &str) -> Result<InitialPosition, anyhow::Error> {
+    match initial_position.to_lowercase().as_str() {
+        "earliest" => Ok(InitialPosition::Earliest),
+        "latest" => Ok(InitialPosition::Latest),
+        _ => Err(anyhow::anyhow!("unknown initial position: {}", initial_position)),
+    }
+}
+
+/// Splits the name of a topic partition, eg.
+/// `persistent://public/sipin/bag.unzip-partition-3`, into the name of the
+/// partitioned topic and the partition index. Returns the original topic and
+/// `None` for non-partitioned topics.
+pub fn split_partition(topic: &str) -> (&str, Option<u32>) {
+    if let Some((base, index)) = topic.rsplit_once("-partition-") {
+        if let Ok(index) = index.parse() {
+            return (base, Some(index));
+        }
+    }
+    (topic, None)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct CloudEvent {
     #[serde(rename = "type")]
@@ -108,3 +448,92 @@ impl DeserializeMessage for CloudEvent {
         serde_json::from_slice(&payload.data)
     }
 }
+
+impl SerializeMessage for CloudEvent {
+    /// Keyed on the correlation_id, so events for the same SIP stay in order
+    /// for Key_Shared subscribers.
+    fn serialize_message(input: Self) -> Result<producer::Message, pulsar::Error> {
+        let payload = serde_json::to_vec(&input).map_err(|e| pulsar::Error::Custom(e.to_string()))?;
+        Ok(producer::Message {
+            payload,
+            partition_key: Some(input.correlation_id),
+            event_time: Some(input.time.timestamp_millis() as u64),
+            ..Default::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config_from(vars: &[(&str, &str)]) -> Config {
+        let mut vars: Vec<(String, String)> = vars.iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        vars.push((String::from("PULSAR_TOPICS"), String::new()));
+        envy::from_iter(vars).unwrap()
+    }
+    #[test]
+    fn pulsar_service_urls_default() {
+        let config = config_from(&[("PULSAR_HOST", "pulsar"), ("PULSAR_PORT", "6650")]);
+        assert_eq!(pulsar_service_urls(&config), vec!["pulsar://pulsar:6650"]);
+    }
+    #[test]
+    fn pulsar_service_urls_with_failover() {
+        let config = config_from(&[
+            ("PULSAR_SERVICE_URLS", "pulsar://broker-1:6650, pulsar://broker-2:6650"),
+            ("PULSAR_FAILOVER_URLS", "pulsar://dr-broker-1:6650"),
+        ]);
+        assert_eq!(pulsar_service_urls(&config), vec![
+            "pulsar://broker-1:6650",
+            "pulsar://broker-2:6650",
+            "pulsar://dr-broker-1:6650",
+        ]);
+    }
+    #[test]
+    fn parse_subscription_type_key_shared() {
+        assert_eq!(parse_subscription_type("Key_Shared").unwrap(), SubType::KeyShared);
+        assert_eq!(parse_subscription_type("key-shared").unwrap(), SubType::KeyShared);
+        assert_eq!(parse_subscription_type("KeyShared").unwrap(), SubType::KeyShared);
+    }
+    #[test]
+    fn parse_subscription_type_exclusive() {
+        assert_eq!(parse_subscription_type("Exclusive").unwrap(), SubType::Exclusive);
+    }
+    #[test]
+    fn split_partition_partitioned() {
+        let (topic, partition) = split_partition("persistent://public/sipin/bag.unzip-partition-3");
+        assert_eq!(topic, "persistent://public/sipin/bag.unzip");
+        assert_eq!(partition, Some(3));
+    }
+    #[test]
+    fn split_partition_non_partitioned() {
+        let (topic, partition) = split_partition("persistent://public/sipin/bag.unzip");
+        assert_eq!(topic, "persistent://public/sipin/bag.unzip");
+        assert_eq!(partition, None);
+    }
+    #[test]
+    fn parse_initial_position_earliest() {
+        assert!(matches!(parse_initial_position("earliest").unwrap(), InitialPosition::Earliest));
+    }
+    #[test]
+    fn parse_initial_position_unknown() {
+        assert!(parse_initial_position("middle").is_err());
+    }
+    #[test]
+    fn parse_subscription_type_unknown() {
+        assert!(parse_subscription_type("Broadcast").is_err());
+    }
+    #[tokio::test]
+    #[ignore = "needs PULSAR2DB_TEST_POSTGRES"]
+    async fn shared_client_reconnects() {
+        let admin = testing::database("shared_client_reconnects", testing::DDL).await;
+        let shared = testing::shared_connection("shared_client_reconnects").await;
+        let client = shared.get().await.unwrap();
+        let pid: i32 = client.query_one("SELECT pg_backend_pid()", &[]).await.unwrap().get(0);
+        admin.execute("SELECT pg_terminate_backend($1)", &[&pid]).await.unwrap();
+        assert!(client.query_one("SELECT 1", &[]).await.is_err());
+        shared.get().await.unwrap().query_one("SELECT 1", &[]).await.unwrap();
+    }
+}
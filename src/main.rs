@@ -1,22 +1,35 @@
-#[macro_use]
-extern crate serde;
-use futures::TryStreamExt;
+use clap::{Parser, Subcommand};
+use futures::{FutureExt, TryStreamExt};
+use percent_encoding::percent_decode_str;
 use pulsar::{
-    message::proto::command_subscribe::SubType, message::Payload, Consumer,
-    DeserializeMessage, Pulsar, TokioExecutor,
+    consumer::{ConsumerOptions, DeadLetterPolicy, Message},
+    message::proto::command_subscribe::SubType, ConnectionRetryOptions, Consumer, Pulsar,
+    TokioExecutor,
 };
-use tokio_postgres::NoTls;
+use tokio::sync::{broadcast, mpsc, oneshot, Mutex, Notify};
+use tokio_postgres::{types::ToSql, Client, GenericClient, Row};
 use pulsar2db::*;
+use pulsar2db::api::ApiState;
+use pulsar2db::changes::{self, ChangeSinks, StatusChange};
+use pulsar2db::export::{self, ExportArgs};
+use pulsar2db::metrics::Metrics;
+use pulsar2db::outbox::{OutboxRelay, PulsarPublisher};
+use pulsar2db::partitions::{valid_schema_name, PartitionManager};
+use pulsar2db::projection::{load_projections, Projection};
+use pulsar2db::report::{self, ReportArgs};
+use pulsar2db::s3::{CpMapping, CpMappingSource, S3Filter};
+use pulsar2db::stalled::{parse_stalled_timeouts, StalledDetector};
+use pulsar2db::webhooks::{WebhookSender, Webhooks};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::panic::AssertUnwindSafe;
 use std::path::Path;
-
-#[derive(Serialize, Deserialize)]
-struct TestData {
-    data: String,
-}
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 
 // Store our list of topics as an array of string slices.
 // The order of the topics is the natural order of an event.
-const TOPICS: [&'static str; 16] = [
+const TOPICS: [&str; 16] = [
     // All topics in de `sipin` namespace.
     "public/sipin/s3.object.create",
     "public/sipin/bag.transfer",
@@ -37,6 +50,75 @@ const TOPICS: [&'static str; 16] = [
     "public/default/be.meemoo.sipin.aip.transfer",
 ];
 
+// The pipeline stage each event type marks the end of, used to compute the
+// time spent in every stage.
+const STAGES: [(&str, &str); 16] = [
+    ("persistent://public/sipin/s3.object.create", "create"),
+    ("persistent://public/sipin/bag.transfer", "transfer"),
+    ("persistent://public/sipin/bag.unzip", "unzip"),
+    ("persistent://public/sipin/bag.validate", "validate"),
+    ("persistent://public/sipin/sip.validate.xsd", "validate_xsd"),
+    ("persistent://public/sipin/sip.loadgraph", "loadgraph"),
+    ("persistent://public/sipin/sip.validate.shacl", "validate_shacl"),
+    ("persistent://public/sipin/mh-sip.create", "mh_sip_create"),
+    ("persistent://public/sipin/mh-sip.transfer", "mh_sip_transfer"),
+    // Legacy SIPIN
+    ("be.meemoo.sipin.sip.create", "create"),
+    ("be.meemoo.sipin.bag.transfer", "transfer"),
+    ("be.meemoo.sipin.bag.unzip", "unzip"),
+    ("be.meemoo.sipin.bag.validate", "validate"),
+    ("be.meemoo.sipin.sip.validate", "validate_xsd"),
+    ("be.meemoo.sipin.aip.create", "mh_sip_create"),
+    ("be.meemoo.sipin.aip.transfer", "mh_sip_transfer"),
+];
+
+// Event types for which only the stage is recorded: they don't change the
+// status of the SIP.
+const STAGE_ONLY_EVENTS: [&str; 4] = [
+    "persistent://public/sipin/bag.transfer",
+    "persistent://public/sipin/sip.validate.xsd",
+    "persistent://public/sipin/sip.loadgraph",
+    "persistent://public/sipin/sip.validate.shacl",
+];
+
+// Event type of the S3 notifications for uploaded SIPs.
+const S3_OBJECT_CREATE: &str = "persistent://public/sipin/s3.object.create";
+
+// Number of messages that can be queued per lane before the consumer waits.
+const LANE_CAPACITY: usize = 100;
+
+// Number of status changes buffered for every listener.
+const CHANGES_CAPACITY: usize = 1000;
+
+/// What to do with a message once its event has been handled.
+enum Outcome {
+    Ack(Message<CloudEvent>),
+    Nack(Message<CloudEvent>),
+}
+
+/// The lanes handling the events, and the channel on which they hand back
+/// the messages to be acknowledged.
+struct Lanes {
+    senders: Vec<mpsc::Sender<Job>>,
+    outcomes: mpsc::UnboundedReceiver<Outcome>,
+}
+
+/// A received message together with its deserialized event, as handed to a
+/// lane.
+struct Job {
+    msg: Message<CloudEvent>,
+    event: CloudEvent,
+}
+
+/// What the lanes need, besides their own Postgres connection, to handle
+/// events.
+struct Context {
+    metrics: Arc<Metrics>,
+    s3_filter: S3Filter,
+    cp_mapping: RwLock<CpMapping>,
+    sinks: Arc<ChangeSinks>,
+}
+
 // Helper functions
 
 /// Splits a string by the underscore character and returns the first
@@ -50,16 +132,1019 @@ fn split_pid_by_underscore(pid: &str) -> &str{
     result[0]
 }
 
-/// Return the filename from a given path
+/// Return the filename from a given path, if it has one.
 fn filename_from_path(full_path: Option<&str>) -> Option<&str> {
-    let path = Path::new(full_path.unwrap());
-    let filename = path.file_name().unwrap();
-    filename.to_str()
+    Path::new(full_path?).file_name()?.to_str()
+}
+
+/// Return the base pid (see `split_pid_by_underscore`) of an event that
+/// should carry one.
+fn event_pid(data: &CloudEvent) -> Result<&str, anyhow::Error> {
+    match data.data["pid"].as_str() {
+        Some(pid) => Ok(split_pid_by_underscore(pid)),
+        None => Err(anyhow::anyhow!("no pid in {} event", &data.type_field)),
+    }
+}
+
+
+/// Return the key Pulsar uses to route a message to a Key_Shared consumer:
+/// the ordering key if set, the partition key otherwise.
+fn routing_key<T>(msg: &Message<T>) -> Option<String> {
+    match &msg.metadata().ordering_key {
+        Some(key) => Some(String::from_utf8_lossy(key).into_owned()),
+        None => msg.key(),
+    }
+}
+
+/// Pick the lane for a correlation_id.
+///
+/// Events with the same correlation_id always end up in the same lane, so
+/// they are handled in the order in which they were received.
+fn lane_for(correlation_id: &str, lanes: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    correlation_id.hash(&mut hasher);
+    (hasher.finish() % lanes as u64) as usize
+}
+
+/// Returns the MD5 hash from an S3 ETag, or `None` if the ETag isn't an MD5
+/// hash, eg. for multipart uploads (`<hash>-<number of parts>`).
+fn md5_from_etag(etag: &str) -> Option<&str> {
+    let etag = etag.trim_matches('"');
+    match etag.len() == 32 && etag.chars().all(|c| c.is_ascii_hexdigit()) {
+        true => Some(etag),
+        false => None,
+    }
+}
+
+/// A single object from the `Records` of an S3 notification.
+struct S3Record<'a> {
+    host: Option<&'a str>,
+    bucket: Option<&'a str>,
+    key: Option<String>,
+    size: Option<i64>,
+    md5: Option<&'a str>,
+}
+
+/// Returns every object in the `Records` of an S3 notification, with their
+/// keys URL-decoded.
+fn s3_records(data: &serde_json::Value) -> Vec<S3Record<'_>> {
+    let records = match data["s3_message"]["Records"].as_array() {
+        Some(records) => records,
+        None => return Vec::new(),
+    };
+    records.iter()
+        .map(|record| {
+            let s3 = &record["s3"];
+            S3Record {
+                host: s3["domain"]["s3-endpoint"].as_str(),
+                bucket: s3["bucket"]["name"].as_str(),
+                key: s3["object"]["key"].as_str().map(decode_s3_key),
+                size: s3["object"]["size"].as_i64(),
+                md5: s3["object"]["eTag"].as_str().and_then(md5_from_etag),
+            }
+        })
+        .collect()
+}
+
+/// Decode an object key from an S3 notification, which is URL-encoded with
+/// spaces as `+`.
+fn decode_s3_key(key: &str) -> String {
+    percent_decode_str(&key.replace('+', " ")).decode_utf8_lossy().into_owned()
+}
+
+/// The correlation_id for the object at `index` in an S3 notification, when
+/// it isn't the first object that passes the S3 filter. That one keeps the
+/// correlation_id of the event, so the events further down the pipeline can
+/// be matched to it. No event carries a suffixed correlation_id, so those
+/// SIPs stay at S3_OBJECT_CREATED.
+fn record_correlation_id(correlation_id: &str, index: usize) -> String {
+    format!("{}-{}", correlation_id, index)
+}
+
+/// Returns true if the upsert returning `row` made the essence checksum
+/// calculated by meemoo (manifest) and the one delivered in the sidecar
+/// differ. A redelivered event finds them differing already.
+fn checksum_mismatch_started(row: &Row) -> bool {
+    let old: Option<bool> = row.get("old_checksum_mismatch");
+    let new: Option<bool> = row.get("checksum_mismatch");
+    new == Some(true) && old != Some(true)
+}
+
+/// Returns the pipeline stage an event type marks the end of.
+fn stage_for(event_type: &str) -> Option<&'static str> {
+    // Some bag transfer events carry the full name of their legacy topic.
+    let event_type = event_type.trim_start_matches("persistent://public/default/");
+    STAGES.iter()
+        .find(|(stage_event_type, _)| *stage_event_type == event_type)
+        .map(|(_, stage)| *stage)
+}
+
+/// Record the time at which a SIP reached the end of a pipeline stage. When a
+/// stage is repeated, the most recent event is kept. Returns 0 if the event
+/// was already recorded, or an event that came later.
+async fn record_stage<C: GenericClient>(client: &C, data: &CloudEvent, stage: &str) -> Result<u64, tokio_postgres::Error> {
+    client.execute(
+        "INSERT INTO sipin_sip_stages (correlation_id, stage, event_type, event_date, outcome)
+        VALUES ($1, $2, $3, $4, $5)
+        ON CONFLICT (correlation_id, stage) DO UPDATE SET
+            event_type = EXCLUDED.event_type,
+            event_date = EXCLUDED.event_date,
+            outcome = EXCLUDED.outcome
+        WHERE sipin_sip_stages.event_date <= EXCLUDED.event_date
+            AND (sipin_sip_stages.event_type, sipin_sip_stages.event_date, sipin_sip_stages.outcome)
+                IS DISTINCT FROM (EXCLUDED.event_type, EXCLUDED.event_date, EXCLUDED.outcome)", &[
+            &data.correlation_id.as_str(),
+            &stage,
+            &data.type_field.as_str(),
+            &data.time,
+            &data.outcome.as_str(),
+        ],
+    ).await
+}
+
+/// Record a failed stage of an event that doesn't change the status, see
+/// `record_stage`, and add the webhook delivery reporting it in the same
+/// transaction, since no status change does. A redelivered event isn't
+/// reported again.
+async fn record_failed_stage(client: &mut Client, context: &Context, data: &CloudEvent, stage: &str) -> Result<u64, tokio_postgres::Error> {
+    let transaction = client.transaction().await?;
+    let recorded = record_stage(&transaction, data, stage).await?;
+    let row = match recorded {
+        0 => None,
+        _ => transaction.query_opt(
+            "SELECT status, last_event_date, first_event_date, cp_id, pid FROM sipin_sips WHERE correlation_id = $1",
+            &[&data.correlation_id],
+        ).await?,
+    };
+    let mut enqueued = false;
+    if let Some(row) = row {
+        let change = StatusChange::from_failed_stage(&row, data, stage);
+        enqueued = context.sinks.record_failed_stage(&transaction, &change).await?;
+    }
+    transaction.commit().await?;
+    if enqueued {
+        context.sinks.webhook_wake.notify_one();
+    }
+    Ok(recorded)
+}
+
+/// Log the result of an upsert for a create event. Returns true if the state
+/// was written.
+fn log_insert_result(data: &CloudEvent, res: Result<bool, tokio_postgres::Error>) -> Result<bool, anyhow::Error> {
+    match res {
+        Ok(inserted) => {
+            match inserted {
+                true => log::debug!("Row created for event {}", &data.type_field.as_str()),
+                false => log::debug!("Row for correlation_id {} already present, filled in creation columns", &data.correlation_id.as_str()),
+            };
+            Ok(true)
+        },
+        Err(error) => {
+            log::error!("Problem: {:?}", error);
+            Err(error.into())
+        },
+    }
+}
+
+/// Log the result of an upsert for an update event. Returns true if the state
+/// was written.
+fn log_update_result(data: &CloudEvent, res: Result<bool, tokio_postgres::Error>) -> Result<bool, anyhow::Error> {
+    match res {
+        Ok(inserted) => {
+            match inserted {
+                true => log::warn!("Created placeholder row for correlation_id {}: event {} arrived before the create event", &data.correlation_id.as_str(), &data.type_field.as_str()),
+                false => log::debug!("Row updated for event {}", &data.type_field.as_str()),
+            };
+            Ok(true)
+        },
+        Err(error) => {
+            log::error!("Problem: {:?}", error);
+            Err(error.into())
+        },
+    }
+}
+
+/// Build the update statement for `upsert_state`. The parameters are the
+/// correlation_id, event time, event type and status, followed by one
+/// parameter per column and then one per default. Columns are overwritten
+/// when the parameter has a value, defaults only fill in a column without
+/// one.
+fn update_statement(columns: &[&str], defaults: &[&str]) -> String {
+    let mut updates = String::new();
+    for (i, column) in columns.iter().enumerate() {
+        updates.push_str(&format!(",\n            {0} = COALESCE(${1}, {0})", column, i + 5));
+    }
+    for (i, column) in defaults.iter().enumerate() {
+        updates.push_str(&format!(",\n            {0} = COALESCE({0}, ${1})", column, columns.len() + i + 5));
+    }
+    format!(
+        "UPDATE sipin_sips SET
+            first_event_date = LEAST(first_event_date, $2),
+            last_event_type = CASE WHEN last_event_date <= $2 THEN $3 ELSE last_event_type END,
+            status = CASE WHEN last_event_date <= $2 THEN $4 ELSE status END,
+            last_event_date = GREATEST(last_event_date, $2),
+            stalled = false{}
+        FROM (SELECT row_id AS old_row_id, status AS old_status, last_event_date AS old_event_date,
+                checksum_mismatch AS old_checksum_mismatch
+            FROM sipin_sips WHERE correlation_id = $1 FOR UPDATE) AS old
+        WHERE correlation_id = $1 AND row_id = old_row_id
+        RETURNING old_status, old_event_date, status, first_event_date, cp_id, pid,
+            old_checksum_mismatch, checksum_mismatch",
+        updates,
+    )
+}
+
+/// Build the insert statement for `upsert_state`, with the same parameters as
+/// `update_statement` for the columns followed by the defaults.
+fn insert_statement(columns: &[&str]) -> String {
+    let mut names = String::new();
+    let mut values = String::new();
+    for (i, column) in columns.iter().enumerate() {
+        names.push_str(&format!(", {}", column));
+        values.push_str(&format!(", ${}", i + 5));
+    }
+    format!(
+        "INSERT INTO sipin_sips (
+            correlation_id, first_event_date, last_event_type, last_event_date, status{})
+        VALUES ($1, $2, $3, $2, $4{})
+        RETURNING NULL::text AS old_status, NULL::timestamptz AS old_event_date,
+            status, first_event_date, cp_id, pid,
+            NULL::bool AS old_checksum_mismatch, checksum_mismatch",
+        names, values,
+    )
+}
+
+/// Upsert the state of a SIP based on an event, setting its status and the
+/// given columns. Returns true if a new row was created.
+///
+/// When the update makes the essence checksums differ, this is logged and
+/// counted once, however often the event is delivered.
+///
+/// The update runs in a transaction. When the status changed, the change is
+/// recorded in `Context.sinks` in that same transaction: the notification,
+/// the status changed event in the outbox and the webhook call for the CP.
+/// After committing, the change is broadcast to its listeners.
+///
+/// When no row exists yet for the correlation_id, eg. because an update
+/// event arrived before the create event, a row is created with the event as
+/// its first event. Events can arrive out of order, eg. when they were
+/// published on different topics or partitions: the status and last event are
+/// therefore only overwritten if the event is not older than the last event
+/// seen for the SIP. Columns without a value in the event keep their current
+/// value.
+///
+/// This is an update followed by an insert rather than an `ON CONFLICT`
+/// upsert, since a partitioned `sipin_sips` can't have a unique constraint
+/// on the correlation_id alone. Instead, the transaction first takes an
+/// advisory lock on the correlation_id, so upserts for the same SIP, by any
+/// lane or instance, run one after the other and only the first one inserts.
+/// Should the insert still hit the unique constraint on the correlation_id of
+/// a non-partitioned table, eg. because a row was inserted by hand, the update
+/// is retried.
+async fn upsert_state(
+    client: &mut Client,
+    context: &Context,
+    data: &CloudEvent,
+    status: &str,
+    columns: &[(&str, &(dyn ToSql + Sync))],
+) -> Result<bool, tokio_postgres::Error> {
+    upsert_state_with_defaults(client, context, data, status, columns, &[]).await
+}
+
+/// Like `upsert_state`, with `defaults`: columns that are only set when they
+/// have no value yet, so they don't overwrite a value from another event.
+async fn upsert_state_with_defaults(
+    client: &mut Client,
+    context: &Context,
+    data: &CloudEvent,
+    status: &str,
+    columns: &[(&str, &(dyn ToSql + Sync))],
+    defaults: &[(&str, &(dyn ToSql + Sync))],
+) -> Result<bool, tokio_postgres::Error> {
+    let names: Vec<&str> = columns.iter().map(|(column, _)| *column).collect();
+    let default_names: Vec<&str> = defaults.iter().map(|(column, _)| *column).collect();
+    let mut params: Vec<&(dyn ToSql + Sync)> = vec![
+        &data.correlation_id,
+        &data.time,
+        &data.type_field,
+        &status,
+    ];
+    params.extend(columns.iter().chain(defaults).map(|(_, value)| *value));
+    let update = update_statement(&names, &default_names);
+    let mut transaction = client.transaction().await?;
+    transaction.execute("SELECT pg_advisory_xact_lock(hashtext($1))", &[&data.correlation_id]).await?;
+    let (row, inserted) = match transaction.query_opt(update.as_str(), &params).await? {
+        Some(row) => (row, false),
+        None => {
+            // A failed insert aborts the transaction, up to the savepoint.
+            let savepoint = transaction.savepoint("insert").await?;
+            match savepoint.query_one(insert_statement(&[names, default_names].concat()).as_str(), &params).await {
+                Ok(row) => {
+                    savepoint.commit().await?;
+                    (row, true)
+                },
+                Err(e) if e.as_db_error().and_then(|e| e.constraint()) == Some("sipin_sips_correlation_id_key") => {
+                    savepoint.rollback().await?;
+                    (transaction.query_one(update.as_str(), &params).await?, false)
+                },
+                Err(e) => return Err(e),
+            }
+        },
+    };
+    let change = StatusChange::from_row(&row, data);
+    if let Some(change) = &change {
+        context.sinks.record(&transaction, change).await?;
+    }
+    transaction.commit().await?;
+    if checksum_mismatch_started(&row) {
+        log::warn!("Checksum mismatch for correlation_id {}: manifest {:?}, sidecar {:?}", &data.correlation_id.as_str(),
+            data.data["md5_hash_essence_manifest"].as_str(), data.data["md5_hash_essence_sidecar"].as_str());
+        context.metrics.checksum_mismatches.inc();
+    }
+    if let Some(change) = change {
+        context.sinks.committed(change);
+    }
+    Ok(inserted)
+}
+
+/// Update the state in the database for a single event. Returns true if the
+/// state was written, or the error if it could not be: a Postgres error, or
+/// an event that lacks what its type needs.
+async fn handle_event(client: &mut Client, context: &Context, data: &CloudEvent) -> Result<bool, anyhow::Error> {
+    let stage = stage_for(&data.type_field);
+    let stage_only = STAGE_ONLY_EVENTS.contains(&data.type_field.as_str());
+    // For S3 notifications, the stage is recorded per accepted object.
+    if let Some(stage) = stage.filter(|_| data.type_field != S3_OBJECT_CREATE) {
+        let recorded = match stage_only && data.outcome != "success" {
+            true => record_failed_stage(client, context, data, stage).await,
+            false => record_stage(client, data, stage).await,
+        };
+        if let Err(error) = recorded {
+            log::error!("Problem: {:?}", error);
+            return Err(error.into());
+        }
+    }
+    match data.type_field.as_str() {
+        // Sipin S3 object create event: sip uploaded to S3. A notification
+        // can hold several objects, each of which is a SIP.
+        S3_OBJECT_CREATE => {
+            let status: &str = "S3_OBJECT_CREATED";
+            let records = s3_records(&data.data);
+            if records.is_empty() {
+                log::warn!("No records in S3 notification for correlation_id {}", &data.correlation_id.as_str());
+                return Ok(false);
+            }
+            let mut written = false;
+            for (i, record) in records.iter().enumerate() {
+                if !context.s3_filter.accepts(record.bucket, record.key.as_deref()) {
+                    log::debug!("Ignoring S3 object {:?} in bucket {:?}", &record.key, &record.bucket);
+                    context.metrics.s3_objects_ignored.with_label_values(&[record.bucket.unwrap_or_default()]).inc();
+                    continue;
+                }
+                // The first object that passes the filter keeps the
+                // correlation_id of the event.
+                let record_event;
+                let event = match written {
+                    false => data,
+                    true => {
+                        record_event = CloudEvent {
+                            correlation_id: record_correlation_id(&data.correlation_id, i),
+                            ..data.clone()
+                        };
+                        &record_event
+                    },
+                };
+                // No later event carries a suffixed correlation_id, so those
+                // SIPs end here. They are marked as ignored, and not flagged
+                // as stalled.
+                let resolution = (event.correlation_id != data.correlation_id).then_some("ignored");
+                let bag_name = record.key.as_deref().and_then(|key| key.rsplit('/').next());
+                if let Some(stage) = stage {
+                    record_stage(client, event, stage).await?;
+                }
+                let cp_id = context.cp_mapping.read().unwrap()
+                    .cp_id(record.bucket, record.key.as_deref())
+                    .map(String::from);
+                // The cp_id from the mapping doesn't overwrite the one of
+                // the mh-sip.create event, should that arrive first.
+                let res = upsert_state_with_defaults(client, context, event, status, &[
+                    ("bag_name", &bag_name),
+                    ("ingest_host", &record.host),
+                    ("ingest_bucket", &record.bucket),
+                    ("ingest_path_or_key", &record.key),
+                    ("bag_filesize", &record.size),
+                    ("md5_hash_sip", &record.md5),
+                    ("resolution", &resolution),
+                ], &[
+                    ("cp_id", &cp_id),
+                ]).await;
+                log_insert_result(event, res)?;
+                written = true;
+            }
+            Ok(written)
+        },
+        // Legacy sip create event: sip created on FTP
+        "be.meemoo.sipin.sip.create" => {
+            let status: &str = "SIP_CREATED";
+            let Some(filename) = filename_from_path(data.data["path"].as_str()) else {
+                return Err(anyhow::anyhow!("no filename in the path of {} event", &data.type_field));
+            };
+            let res = upsert_state(client, context, data, status, &[
+                ("bag_name", &filename),
+                ("cp_id", &data.data["cp_id"].as_str()),
+                ("local_id", &data.data["local_id"].as_str()),
+                ("md5_hash_essence_manifest", &data.data["md5_hash_essence_manifest"].as_str()),
+                ("md5_hash_essence_sidecar", &data.data["md5_hash_essence_sidecar"].as_str()),
+                ("essence_filename", &data.data["essence_filename"].as_str()),
+                ("essence_filesize", &data.data["essence_filesize"].as_i64()),
+                ("ingest_host", &data.data["host"].as_str()),
+                ("ingest_path_or_key", &data.data["path"].as_str()),
+                ("bag_filesize", &data.data["bag_filesize"].as_i64()),
+            ]).await;
+            log_insert_result(data, res)
+        },
+        // Legacy and new bag transfer events
+        "be.meemoo.sipin.bag.transfer" | "persistent://public/default/be.meemoo.sipin.bag.transfer" => {
+            let res = upsert_state(client, context, data, "BAG_TRANSFERRED_TO_SIPIN", &[]).await;
+            log_update_result(data, res)
+        },
+        // Legacy and new bag unzip events
+        "be.meemoo.sipin.bag.unzip" | "persistent://public/sipin/bag.unzip" => {
+            let res = upsert_state(client, context, data, "BAG_UNZIPPED", &[]).await;
+            log_update_result(data, res)
+        },
+        // Legacy and new bag validate events
+        "be.meemoo.sipin.bag.validate" | "persistent://public/sipin/bag.validate" => {
+            let res = upsert_state(client, context, data, "BAG_VALIDATED", &[
+                ("md5_hash_sip", &data.data["md5_hash_sip"].as_str()),
+            ]).await;
+            log_update_result(data, res)
+        },
+        // Legacy sip validate event
+        "be.meemoo.sipin.sip.validate" => {
+            let res = upsert_state(client, context, data, "SIP_VALIDATED", &[]).await;
+            log_update_result(data, res)
+        },
+        // Legacy aip (mh-sip) create event
+        "be.meemoo.sipin.aip.create" => {
+            let status: &str = "AIP_CREATED";
+            let pid = event_pid(data)?;
+            let res = upsert_state(client, context, data, status, &[
+                ("cp_id", &data.data["cp_id"].as_str()),
+                ("pid", &pid),
+            ]).await;
+            log_update_result(data, res)
+        },
+        // Sipin mh-sip create event
+        "persistent://public/sipin/mh-sip.create" => {
+            let status: &str = "MH-SIP_CREATED";
+            let pid = event_pid(data)?;
+            let res = upsert_state(client, context, data, status, &[
+                ("cp_id", &data.data["cp_id"].as_str()),
+                ("pid", &pid),
+                ("sip_profile", &data.data["sip_profile"].as_str()),
+            ]).await;
+            log_update_result(data, res)
+        },
+        // Legacy aip and sipin mh-sip transfer events: archived in MediaHaven
+        "be.meemoo.sipin.aip.transfer" | "persistent://public/sipin/mh-sip.transfer" => {
+            let res = upsert_state(client, context, data, "AIP_DELIVERED_TO_MAM", &[]).await;
+            log_update_result(data, res)
+        },
+        // Events for which only the stage is recorded
+        _ if stage.is_some() => Ok(true),
+        _ => {
+            log::warn!("Unknown event type: {:#?}", &data.type_field.as_str());
+            Ok(false)
+        },
+    }
+}
+
+/// Look up the number of partitions of every topic, in the order of
+/// `TOPICS`. Non-partitioned topics have 0 partitions.
+async fn partition_counts(pulsar: &Pulsar<TokioExecutor>, metrics: &Metrics) -> Result<Vec<u32>, pulsar::Error> {
+    let mut counts = Vec::with_capacity(TOPICS.len());
+    for topic in TOPICS {
+        let count = pulsar.lookup_partitioned_topic_number(topic).await?;
+        metrics.topic_partitions.with_label_values(&[topic]).set(count as i64);
+        counts.push(count);
+    }
+    Ok(counts)
+}
+
+/// The retries of an event that could not be written to Postgres. Events
+/// are retried in place, backing off up to `LANE_RETRY_MAX_BACKOFF`, so the events
+/// after them wait and stay in order. A lost Postgres connection is reopened
+/// without counting as a retry.
+struct Retries<'a> {
+    config: &'a Config,
+    backoff: Duration,
+    retries: u32,
+}
+
+impl<'a> Retries<'a> {
+    fn new(config: &'a Config) -> Retries<'a> {
+        Retries {
+            config,
+            backoff: Duration::from_millis(config.pulsar_reconnect_min_backoff_ms),
+            retries: 0,
+        }
+    }
+
+    /// Wait before the next attempt at `event` after `error`, reconnecting
+    /// `client` if needed. Returns false once `LANE_RETRIES` retries failed:
+    /// the message is then to be negatively acknowledged, so Pulsar
+    /// redelivers it right away.
+    async fn wait(&mut self, client: &mut Client, event: &CloudEvent, error: &(dyn std::fmt::Debug + Sync)) -> bool {
+        if client.is_closed() {
+            log::error!("Lost the connection to Postgres: {:?}. Reconnecting in {:?}", error, self.backoff);
+            tokio::time::sleep(self.backoff).await;
+            match connect_postgres(self.config).await {
+                Ok(reconnected) => *client = reconnected,
+                Err(e) => log::error!("Could not reconnect to Postgres: {:?}", e),
+            }
+        } else if self.retries < self.config.lane_retries {
+            self.retries += 1;
+            log::warn!("Retrying correlation_id {} in {:?} ({}/{})", &event.correlation_id.as_str(), self.backoff, self.retries, self.config.lane_retries);
+            tokio::time::sleep(self.backoff).await;
+        } else {
+            log::error!("Giving up on correlation_id {} after {} retries: handing it back to Pulsar", &event.correlation_id.as_str(), self.retries);
+            return false;
+        }
+        self.backoff = (self.backoff * 2).min(Duration::from_secs(self.config.lane_retry_max_backoff));
+        true
+    }
+}
+
+/// Returns true if handling an event again may get past `error`: when the
+/// connection was lost (SQLSTATE class 08, or an I/O error), or on a
+/// serialization failure or deadlock (class 40). Other errors, such as a value
+/// that doesn't fit its column (class 22) or a violated constraint (class 23),
+/// fail the same way every time.
+fn is_transient(error: &tokio_postgres::Error) -> bool {
+    match error.code() {
+        Some(code) => code.code().starts_with("08") || code.code().starts_with("40"),
+        None => error.is_closed() || std::error::Error::source(error).is_some_and(|source| source.is::<std::io::Error>()),
+    }
+}
+
+/// Log an event that is skipped after an error that retrying doesn't fix.
+/// Its message is acknowledged, so it doesn't hold up the events after it.
+fn skip_event(metrics: &Metrics, event: &CloudEvent, error: &(dyn std::fmt::Debug + Sync)) {
+    log::error!("Skipping event {} ({}) for correlation_id {}: {:?}", &event.id, &event.type_field, &event.correlation_id, error);
+    metrics.events_skipped.with_label_values(&[event.type_field.as_str()]).inc();
+}
+
+/// Handle the jobs of a single lane one by one, and pass every handled
+/// message back to the consumer loop to be acknowledged. Events that failed
+/// on a transient error are retried, see `Retries`; others are skipped, as
+/// are events whose handling panicked.
+async fn run_lane(
+    config: Arc<Config>,
+    mut client: Client,
+    context: Arc<Context>,
+    mut jobs: mpsc::Receiver<Job>,
+    outcomes: mpsc::UnboundedSender<Outcome>,
+) {
+    while let Some(job) = jobs.recv().await {
+        log::info!("insert into DB: {}, correlation_id: {}", &job.event.type_field.as_str(), &job.event.correlation_id.as_str());
+        let mut retries = Retries::new(&config);
+        let outcome = loop {
+            // A panic would fail the same way on every redelivery. The
+            // transaction it was in is rolled back when dropped.
+            let handled = AssertUnwindSafe(handle_event(&mut client, &context, &job.event)).catch_unwind().await;
+            match handled {
+                Ok(Ok(written)) => {
+                    if written {
+                        context.metrics.observe_event_latency(&job.event);
+                    }
+                    break Outcome::Ack(job.msg);
+                },
+                Ok(Err(e)) if !e.downcast_ref().is_some_and(is_transient) && !client.is_closed() => {
+                    skip_event(&context.metrics, &job.event, &e);
+                    break Outcome::Ack(job.msg);
+                },
+                Ok(Err(e)) => if !retries.wait(&mut client, &job.event, &e).await {
+                    break Outcome::Nack(job.msg);
+                },
+                Err(_) => {
+                    skip_event(&context.metrics, &job.event, &"handling the event panicked");
+                    break Outcome::Ack(job.msg);
+                },
+            }
+        };
+        if outcomes.send(outcome).is_err() {
+            break;
+        }
+    }
+}
+
+/// Why consuming from Pulsar stopped without an error.
+enum SessionEnd {
+    /// The consumer was closed.
+    Stopped,
+    /// A lane is gone: its events can't be handled anymore.
+    LaneStopped,
+    /// A topic was repartitioned: subscribe again to pick up all partitions.
+    Repartitioned,
+}
+
+/// Connect to the first reachable Pulsar cluster, trying the URLs in order.
+async fn connect_pulsar(urls: &[String], retry: &ConnectionRetryOptions) -> Result<Pulsar<TokioExecutor>, pulsar::Error> {
+    let mut last_error = None;
+    for url in urls {
+        log::info!("Connecting to Pulsar on {}", url);
+        match Pulsar::builder(url, TokioExecutor)
+            .with_connection_retry_options(retry.clone())
+            .build()
+            .await
+        {
+            Ok(pulsar) => return Ok(pulsar),
+            Err(e) => {
+                log::warn!("Could not connect to Pulsar on {}: {:?}", url, e);
+                last_error = Some(e);
+            },
+        }
+    }
+    Err(last_error.unwrap_or_else(|| pulsar::Error::Custom(String::from("no Pulsar service URLs configured"))))
+}
+
+/// Exponential backoff between attempts at (re)connecting to Pulsar.
+struct Backoff {
+    min: Duration,
+    max: Duration,
+    delay: Duration,
+}
+
+impl Backoff {
+    fn new(retry: &ConnectionRetryOptions) -> Backoff {
+        Backoff { min: retry.min_backoff, max: retry.max_backoff, delay: retry.min_backoff }
+    }
+
+    /// Wait before the next attempt, and back off further for the one after.
+    async fn wait(&mut self) {
+        tokio::time::sleep(self.delay).await;
+        self.delay = (self.delay * 2).min(self.max);
+    }
+
+    /// Start over from the minimum, once the connection proved to work.
+    fn reset(&mut self) {
+        self.delay = self.min;
+    }
+}
+
+/// Connect to Pulsar and subscribe to the given topics, retrying until it
+/// works. The backoff isn't reset on success: that is up to the caller, once
+/// messages come through.
+async fn connect_and_subscribe(
+    urls: &[String],
+    retry: &ConnectionRetryOptions,
+    backoff: &mut Backoff,
+    config: &Config,
+    topics: &[&str],
+    subscription: &str,
+    subscription_type: SubType,
+) -> (Pulsar<TokioExecutor>, Consumer<CloudEvent, TokioExecutor>) {
+    loop {
+        let session = async {
+            let pulsar = connect_pulsar(urls, retry).await?;
+            let consumer = subscribe(&pulsar, config, topics, subscription, subscription_type).await?;
+            Ok::<_, anyhow::Error>((pulsar, consumer))
+        };
+        match session.await {
+            Ok(session) => return session,
+            Err(e) => {
+                log::error!("Could not subscribe {}: {:?}. Retrying in {:?}", subscription, e, backoff.delay);
+                backoff.wait().await;
+            },
+        }
+    }
+}
+
+/// Relay the events in the outbox to Pulsar, (re)connecting to Pulsar and
+/// Postgres with exponential backoff.
+async fn run_outbox_relay(config: Arc<Config>, mut relay: OutboxRelay) {
+    let urls = pulsar_service_urls(&config);
+    let retry = retry_options(&config);
+    let mut backoff = Backoff::new(&retry);
+    loop {
+        let mut publisher = match connect_pulsar(&urls, &retry).await {
+            Ok(pulsar) => PulsarPublisher::new(pulsar),
+            Err(e) => {
+                log::error!("Could not connect the outbox relay: {:?}. Retrying in {:?}", e, backoff.delay);
+                backoff.wait().await;
+                continue;
+            },
+        };
+        loop {
+            match relay.relay(&mut publisher).await {
+                // A full batch: there may be more waiting.
+                Ok(count) if count as i64 == relay.batch_size => {
+                    backoff.reset();
+                    continue;
+                },
+                Ok(_) => backoff.reset(),
+                Err(e) => {
+                    log::error!("Problem while relaying the outbox: {:?}. Retrying in {:?}", e, backoff.delay);
+                    backoff.wait().await;
+                    if relay.client.is_closed() {
+                        match connect_postgres(&config).await {
+                            Ok(client) => relay.client = client,
+                            Err(e) => log::error!("Could not reconnect the outbox relay to Postgres: {:?}", e),
+                        }
+                    }
+                    // Only errors from Pulsar call for a new connection.
+                    if e.is::<tokio_postgres::Error>() {
+                        continue;
+                    }
+                    break;
+                },
+            }
+            if let Err(e) = relay.clean().await {
+                log::error!("Could not clean up the outbox: {:?}", e);
+            }
+            relay.wait().await;
+        }
+    }
+}
+
+/// Deliver the webhook calls that are due, reconnecting to Postgres when the
+/// connection was lost.
+async fn run_webhook_sender(config: Arc<Config>, mut sender: WebhookSender) {
+    loop {
+        if let Err(e) = sender.send_due().await {
+            log::error!("Problem while delivering webhooks: {:?}", e);
+            if sender.client.is_closed() {
+                match connect_postgres(&config).await {
+                    Ok(client) => sender.client = client,
+                    Err(e) => log::error!("Could not reconnect the webhook sender to Postgres: {:?}", e),
+                }
+            }
+        }
+        sender.wait().await;
+    }
+}
+
+/// Broadcast the status changes notified on `POSTGRES_NOTIFY_CHANNEL` by
+/// every instance, reconnecting to Postgres with exponential backoff. Changes
+/// notified while reconnecting are missed.
+async fn run_change_listener(config: Arc<Config>, broadcast: broadcast::Sender<StatusChange>) {
+    let connection_string = format_postgres_connection_string(&config);
+    let mut backoff = Backoff::new(&retry_options(&config));
+    loop {
+        match changes::listen(&connection_string, &config.postgres_notify_channel, &broadcast).await {
+            Ok(()) => backoff.reset(),
+            Err(e) => log::error!("Could not listen for status changes: {:?}. Retrying in {:?}", e, backoff.delay),
+        }
+        backoff.wait().await;
+    }
+}
+
+/// The options for (re)connecting to Pulsar.
+fn retry_options(config: &Config) -> ConnectionRetryOptions {
+    ConnectionRetryOptions {
+        min_backoff: Duration::from_millis(config.pulsar_reconnect_min_backoff_ms),
+        max_backoff: Duration::from_secs(config.pulsar_reconnect_max_backoff),
+        ..Default::default()
+    }
+}
+
+/// Subscribe to the given topics. Since the subscription is durable, a
+/// consumer that subscribes again on the same cluster continues from the
+/// last acknowledged message.
+async fn subscribe(
+    pulsar: &Pulsar<TokioExecutor>,
+    config: &Config,
+    topics: &[&str],
+    subscription: &str,
+    subscription_type: SubType,
+) -> Result<Consumer<CloudEvent, TokioExecutor>, anyhow::Error> {
+    let initial_position = parse_initial_position(&config.pulsar_initial_position)?;
+    let ack_timeout = match config.pulsar_ack_timeout {
+        0 => None,
+        seconds => Some(Duration::from_secs(seconds)),
+    };
+    let mut builder = pulsar
+        .consumer()
+        .with_topics(topics)
+        .with_consumer_name(&config.pulsar_consumer_name)
+        .with_subscription_type(subscription_type)
+        .with_subscription(subscription)
+        .with_options(ConsumerOptions::default().with_initial_position(initial_position))
+        .with_batch_size(config.pulsar_receiver_queue_size)
+        .with_unacked_message_resend_delay(ack_timeout);
+    if config.pulsar_max_redeliveries > 0 {
+        builder = builder.with_dead_letter_policy(DeadLetterPolicy {
+            max_redeliver_count: config.pulsar_max_redeliveries,
+            dead_letter_topic: config.pulsar_dead_letter_topic.clone(),
+        });
+    }
+    Ok(builder.build().await?)
 }
 
+/// Hand out received messages to the lanes and acknowledge them once handled,
+/// until the consumer stops or a Pulsar error occurs.
+async fn consume(
+    pulsar: &Pulsar<TokioExecutor>,
+    consumer: &mut Consumer<CloudEvent, TokioExecutor>,
+    config: &Config,
+    subscription_type: SubType,
+    metrics: &Arc<Metrics>,
+    lanes: &mut Lanes,
+    counter: &mut usize,
+) -> Result<SessionEnd, anyhow::Error> {
+    // Partitions: the consumer subscribes to every partition that exists at
+    // this point. Watch for topics that get repartitioned afterwards.
+    let initial_counts = partition_counts(pulsar, metrics).await?;
+    for (topic, count) in TOPICS.iter().zip(&initial_counts) {
+        log::info!("Topic {} has {} partitions", topic, count);
+    }
+    log::debug!("Subscribed to: {:?}", consumer.topics());
+    let (repartitioned_tx, mut repartitioned_rx) = oneshot::channel();
+    let partition_check_interval = Duration::from_secs(config.pulsar_partition_check_interval);
+    let pulsar_watch = pulsar.clone();
+    let metrics_watch = metrics.clone();
+    let watcher = tokio::spawn(async move {
+        let mut interval = tokio::time::interval(partition_check_interval);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            match partition_counts(&pulsar_watch, &metrics_watch).await {
+                Ok(counts) if counts != initial_counts => {
+                    log::warn!("Number of partitions changed: {:?} -> {:?}", initial_counts, counts);
+                    let _ = repartitioned_tx.send(());
+                    break;
+                },
+                Ok(_) => (),
+                Err(e) => log::error!("could not look up partitions: {:?}", e),
+            }
+        }
+    });
+
+    let result = loop {
+        tokio::select! {
+            // Stop consuming so we can resubscribe to all partitions.
+            Ok(()) = &mut repartitioned_rx => break Ok(SessionEnd::Repartitioned),
+            // Only acknowledge a message once its event has been handled.
+            Some(outcome) = lanes.outcomes.recv() => {
+                let res = match outcome {
+                    Outcome::Ack(msg) => consumer.ack(&msg).await,
+                    Outcome::Nack(msg) => consumer.nack(&msg).await,
+                };
+                if let Err(e) = res {
+                    break Err(e.into());
+                }
+            },
+            msg = consumer.try_next() => {
+                let msg = match msg {
+                    Ok(Some(msg)) => msg,
+                    Ok(None) => break Ok(SessionEnd::Stopped),
+                    Err(e) => break Err(e.into()),
+                };
+                let (topic, partition) = split_partition(&msg.topic);
+                metrics.observe_message(topic, partition, msg.metadata().publish_time);
+                let data = match msg.deserialize() {
+                    Ok(data) => data,
+                    // It would fail the same way every time.
+                    Err(e) => {
+                        log::error!("Skipping a message on {} that could not be deserialized: {:?}", topic, e);
+                        metrics.undeserializable_messages.with_label_values(&[topic]).inc();
+                        consumer.ack(&msg).await?;
+                        continue;
+                    }
+                };
+
+                if subscription_type == SubType::KeyShared {
+                    let key = routing_key(&msg);
+                    if key.as_deref() != Some(data.correlation_id.as_str()) {
+                        log::warn!("Message key {:?} differs from correlation_id {}: ordering not guaranteed", key, &data.correlation_id.as_str());
+                        metrics.key_mismatches.with_label_values(&[topic]).inc();
+                    }
+                }
+
+                *counter += 1;
+                log::trace!("got {} messages", counter);
+                log::debug!("{:?}", &data);
+                let lane = lane_for(&data.correlation_id, lanes.senders.len());
+                if lanes.senders[lane].send(Job { msg, event: data }).await.is_err() {
+                    break Ok(SessionEnd::LaneStopped);
+                }
+            }
+        }
+    };
+    watcher.abort();
+    result
+}
+
+/// Upsert the events of a projection into its table, one by one, until the
+/// consumer stops or a Pulsar error occurs. Like the lanes, events that failed
+/// on a transient error are retried (see `Retries`) and then negatively
+/// acknowledged, and other failed events are skipped, as are messages that
+/// can't be deserialized.
+async fn consume_projection(
+    projection: &Projection,
+    config: &Config,
+    client: &mut Client,
+    metrics: &Metrics,
+    consumer: &mut Consumer<CloudEvent, TokioExecutor>,
+    counter: &mut usize,
+) -> Result<(), anyhow::Error> {
+    while let Some(msg) = consumer.try_next().await? {
+        *counter += 1;
+        let (topic, partition) = split_partition(&msg.topic);
+        metrics.observe_message(topic, partition, msg.metadata().publish_time);
+        let event = match msg.deserialize() {
+            Ok(event) => event,
+            Err(e) => {
+                log::error!("Projection {}: skipping a message on {} that could not be deserialized: {:?}", &projection.name, topic, e);
+                metrics.undeserializable_messages.with_label_values(&[topic]).inc();
+                consumer.ack(&msg).await?;
+                continue;
+            },
+        };
+        let mut retries = Retries::new(config);
+        loop {
+            match projection.upsert(client, &event).await {
+                Ok(written) => {
+                    if written {
+                        metrics.observe_event_latency(&event);
+                    }
+                    consumer.ack(&msg).await?;
+                    break;
+                },
+                Err(e) if !e.downcast_ref().is_some_and(is_transient) && !client.is_closed() => {
+                    log::error!("Projection {}: problem: {:?}", &projection.name, e);
+                    skip_event(metrics, &event, &e);
+                    consumer.ack(&msg).await?;
+                    break;
+                },
+                Err(e) => {
+                    log::error!("Projection {}: problem: {:?}", &projection.name, e);
+                    if !retries.wait(client, &event, &e).await {
+                        consumer.nack(&msg).await?;
+                        break;
+                    }
+                },
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Build the state of a projection: consume its topics and upsert every
+/// event into its table. Reconnects with backoff when the connection to
+/// Pulsar drops.
+async fn run_projection(
+    projection: Projection,
+    config: Arc<Config>,
+    subscription_type: SubType,
+    mut client: Client,
+    metrics: Arc<Metrics>,
+) {
+    let urls = pulsar_service_urls(&config);
+    let retry = retry_options(&config);
+    let subscription = projection.subscription_name.clone()
+        .unwrap_or_else(|| format!("{}_{}", &config.pulsar_subscription_name, &projection.name));
+    let topics: Vec<&str> = projection.topics.iter().map(String::as_str).collect();
+    let mut backoff = Backoff::new(&retry);
+    let mut counter = 0usize;
+    loop {
+        let (_pulsar, mut consumer) = connect_and_subscribe(&urls, &retry, &mut backoff, &config, &topics, &subscription, subscription_type).await;
+        log::info!("Projection {}: topics={:?}, subscription_name={}, table={}", &projection.name, &topics, &subscription, &projection.table);
+        let received = counter;
+        match consume_projection(&projection, &config, &mut client, &metrics, &mut consumer, &mut counter).await {
+            Ok(()) => return,
+            Err(e) => {
+                if counter > received {
+                    backoff.reset();
+                }
+                log::error!("Projection {}: lost connection to Pulsar: {:?}. Reconnecting in {:?}", &projection.name, e, backoff.delay);
+                backoff.wait().await;
+            },
+        }
+    }
+}
+
+/// Without a subcommand, the service consumes the events and keeps the state
+/// in Postgres. It is configured through the environment, see `.env`.
+#[derive(Parser, Debug)]
+#[command(version, about)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Export the SIPs matching the filters to CSV or XLSX
+    Report(ReportArgs),
+    /// Export the SIPs, and optionally their events, to Parquet files per day
+    Export(ExportArgs),
+}
 
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
+    let cli = Cli::parse();
     env_logger::init();
 
     // Get our configuration from the environment
@@ -69,282 +1154,228 @@ async fn main() -> Result<(), anyhow::Error> {
        Err(error) => panic!("{:#?}", error)
     };
 
-    log::info!("Connecting to Pulsar on {}: topics={:?}, subscription_name={}", &config.pulsar_host, &TOPICS, &config.pulsar_subscription_name);
-    let addr = format_pulsar_connection_string(&config);
-    let pulsar: Pulsar<_> = Pulsar::builder(addr, TokioExecutor).build().await?;
+    match cli.command {
+        Some(Command::Report(args)) => return report::run(&connect_postgres(&config).await?, &args).await,
+        Some(Command::Export(args)) => return export::run(&connect_postgres(&config).await?, &args).await,
+        None => (),
+    }
+    let config = Arc::new(config);
 
-    // Pulsar consumer
-    let mut consumer: Consumer<CloudEvent, _> = pulsar
-        .consumer()
-        .with_topics(&TOPICS)
-        .with_consumer_name(&config.pulsar_consumer_name)
-        .with_subscription_type(SubType::Exclusive)
-        .with_subscription(&config.pulsar_subscription_name)
-        .build()
-        .await?;
+    let subscription_type = parse_subscription_type(&config.pulsar_subscription_type)?;
+    match subscription_type {
+        SubType::Shared => log::warn!("Shared subscription: events for the same correlation_id can be processed out of order"),
+        SubType::KeyShared => log::info!("Key_Shared subscription: events need to be keyed on correlation_id to stay ordered"),
+        _ => (),
+    };
 
     // Postgres client: non-blocking
     log::info!("Connecting to Postgres on {}", &config.postgres_host);
-    let connection_string = format_postgres_connection_string(&config);
-    let (client, connection) = tokio_postgres::connect(&connection_string, NoTls).await?;
+    let mut client = connect_postgres(&config).await?;
+    if config.postgres_create_views {
+        log::info!("Creating reporting views");
+        pulsar2db::schema::create_views(&mut client).await
+            .map_err(|e| anyhow::anyhow!("could not create the reporting views, run upgrade.sql on tables of an earlier version: {}", e))?;
+    }
+    let client = Arc::new(SharedClient::new(format_postgres_connection_string(&config), client));
+
+    // Partition maintenance, on a connection of its own since it runs in a
+    // transaction.
+    if config.postgres_partitioning {
+        if !config.postgres_archive_schema.is_empty() && !valid_schema_name(&config.postgres_archive_schema) {
+            return Err(anyhow::anyhow!("invalid archive schema: {:?}", &config.postgres_archive_schema));
+        }
+        let manager = PartitionManager {
+            client: connect_postgres(&config).await?,
+            interval: Duration::from_secs(config.postgres_partition_maintenance_interval),
+            months_ahead: config.postgres_partitions_ahead,
+            retention_months: config.postgres_retention_months,
+            archive_schema: config.postgres_archive_schema.clone(),
+        };
+        tokio::spawn(manager.run());
+    }
 
-    // The connection object performs the actual communication with the database,
-    // so spawn it off to run on its own.
+    // Metrics endpoint
+    let metrics = Arc::new(Metrics::new()?);
+    let metrics_port = config.metrics_port;
+    let metrics_server = metrics.clone();
     tokio::spawn(async move {
-        if let Err(e) = connection.await {
-            eprintln!("connection error: {}", e);
+        if let Err(e) = pulsar2db::metrics::serve(metrics_server, metrics_port).await {
+            log::error!("metrics server error: {:?}", e);
         }
     });
-    
-    let mut counter = 0usize;
-    while let Some(msg) = consumer.try_next().await? {
-        consumer.ack(&msg).await?;
-        let data = match msg.deserialize() {
-            Ok(data) => data,
-            Err(e) => {
-                log::error!("could not deserialize message: {:?}", e);
-                break;
-            }
+
+    // Status changes published on Pulsar, through the outbox
+    let outbox_wake = Arc::new(Notify::new());
+    if !config.pulsar_output_topic.is_empty() {
+        let relay = OutboxRelay {
+            client: connect_postgres(&config).await?,
+            wake: outbox_wake.clone(),
+            interval: Duration::from_millis(config.outbox_poll_interval_ms),
+            batch_size: config.outbox_batch_size,
+            retention: Duration::from_secs(config.outbox_retention),
         };
+        tokio::spawn(run_outbox_relay(config.clone(), relay));
+    }
 
-        counter += 1;
-        log::trace!("got {} messages", counter);
-        log::debug!("{:?}", &data);
-        log::info!("insert into DB: {}, correlation_id: {}", &data.type_field.as_str(), &data.correlation_id.as_str());
-        match data.type_field.as_str() {
-            // Sipin S3 object create event: sip uploaded to S3
-            "persistent://public/sipin/s3.object.create" => {
-                let status: &str = "S3_OBJECT_CREATED";
-                let res = client.execute(
-                    "INSERT INTO sipin_sips (
-                        correlation_id,
-                        bag_name,
-                        ingest_host,
-                        ingest_bucket,
-                        ingest_path_or_key,
-                        first_event_date,
-                        last_event_type,
-                        last_event_date,
-                        status)
-                    VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)", &[
-                        &data.correlation_id.as_str(),
-                        &data.subject.as_str(),
-                        &data.data["s3_message"]["Records"][0]["s3"]["domain"]["s3-endpoint"].as_str(),
-                        &data.data["s3_message"]["Records"][0]["s3"]["bucket"]["name"].as_str(),
-                        &data.data["s3_message"]["Records"][0]["s3"]["object"]["key"].as_str(),
-                        &data.time,
-                        &data.type_field.as_str(),
-                        &data.time,
-                        &status,
-                    ],
-                ).await;
-                match res {
-                    Ok(rows) => log::debug!("Rows created: {}", rows),
-                    Err(error) => log::error!("Problem: {:?}", error),
-                };
-            },
-            // Legacy sip create event: sip created on FTP
-            "be.meemoo.sipin.sip.create" => {
-                let status: &str = "SIP_CREATED";
-                let filename = filename_from_path(data.data["path"].as_str());
-                let res = client.execute(
-                    "INSERT INTO sipin_sips (
-                        correlation_id,
-                        bag_name,
-                        cp_id,
-                        local_id,
-                        md5_hash_essence_manifest,
-                        md5_hash_essence_sidecar,
-                        essence_filename,
-                        essence_filesize,
-                        ingest_host,
-                        ingest_path_or_key,
-                        bag_filesize,
-                        first_event_date,
-                        last_event_type,
-                        last_event_date,
-                        status)
-                    VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12,
-                            $13, $14, $15)", &[
-                        &data.correlation_id.as_str(),
-                        &filename.unwrap(),
-                        &data.data["cp_id"].as_str(),
-                        &data.data["local_id"].as_str(),
-                        &data.data["md5_hash_essence_manifest"].as_str(),
-                        &data.data["md5_hash_essence_sidecar"].as_str(),
-                        &data.data["essence_filename"].as_str(),
-                        &data.data["essence_filesize"].as_i64(),
-                        &data.data["host"].as_str(),
-                        &data.data["path"].as_str(),
-                        &data.data["bag_filesize"].as_i64(),
-                        &data.time,
-                        &data.type_field.as_str(),
-                        &data.time,
-                        &status,
-                    ],
-                ).await;
-                match res {
-                    Ok(rows) => log::debug!("Rows created: {}", rows),
-                    Err(error) => log::error!("Problem: {:?}", error),
-                };
-            },
-            // Legacy and new bag transfer events
-            "be.meemoo.sipin.bag.transfer" | "persistent://public/default/be.meemoo.sipin.bag.transfer" => {
-                let status: &str = "BAG_TRANSFERRED_TO_SIPIN";
-                let res = client.execute(
-                    "UPDATE sipin_sips SET last_event_type=$1, last_event_date=$2, status=$3
-                    WHERE correlation_id=$4", &[
-                        &data.type_field.as_str(),
-                        &data.time,
-                        &status,
-                        &data.correlation_id.as_str(),
-                    ],
-                ).await;
-                match res {
-                    Ok(rows) => match rows {
-                        0 => log::warn!("No rows updated for event {}! correlation_id {} not present?", &data.type_field.as_str(), &data.correlation_id.as_str()),
-                        1 => log::debug!("Rows updated for event {}: {}", &data.type_field.as_str(), rows),
-                        _ => log::warn!("Rows updated for event {}: {}. More then one record with correlation_id {}", &data.type_field.as_str(), rows, &data.correlation_id.as_str()),
-                    },
-                    Err(error) => log::error!("Problem: {:?}", error),
-                };
-            },
-            // Legacy and new bag unzip events
-            "be.meemoo.sipin.bag.unzip" | "persistent://public/sipin/bag.unzip" => {
-                let status: &str = "BAG_UNZIPPED";
-                let res = client.execute(
-                    "UPDATE sipin_sips SET last_event_type=$1, last_event_date=$2, status=$3
-                    WHERE correlation_id=$4", &[
-                        &data.type_field.as_str(),
-                        &data.time,
-                        &status,
-                        &data.correlation_id.as_str(),
-                    ],
-                ).await;
-                match res {
-                    Ok(rows) => match rows {
-                        0 => log::warn!("No rows updated for event {}! correlation_id {} not present?", &data.type_field.as_str(), &data.correlation_id.as_str()),
-                        1 => log::debug!("Rows updated for event {}: {}", &data.type_field.as_str(), rows),
-                        _ => log::warn!("Rows updated for event {}: {}. More then one record with correlation_id {}", &data.type_field.as_str(), rows, &data.correlation_id.as_str()),
-                    },
-                    Err(error) => log::error!("Problem: {:?}", error),
-                };
-            },
-            // Legacy and new bag validate events
-            "be.meemoo.sipin.bag.validate" | "persistent://public/sipin/bag.validate" => {
-                let status: &str = "BAG_VALIDATED";
-                let res = client.execute(
-                    "UPDATE sipin_sips SET last_event_type=$1, last_event_date=$2, status=$3
-                    WHERE correlation_id=$4", &[
-                        &data.type_field.as_str(),
-                        &data.time,
-                        &status,
-                        &data.correlation_id.as_str(),
-                    ],
-                ).await;
-                match res {
-                    Ok(rows) => match rows {
-                        0 => log::warn!("No rows updated for event {}! correlation_id {} not present?", &data.type_field.as_str(), &data.correlation_id.as_str()),
-                        1 => log::debug!("Rows updated for event {}: {}", &data.type_field.as_str(), rows),
-                        _ => log::warn!("Rows updated for event {}: {}. More then one record with correlation_id {}", &data.type_field.as_str(), rows, &data.correlation_id.as_str()),
-                    },
-                    Err(error) => log::error!("Problem: {:?}", error),
-                };
-            },
-            // Legacy sip validate event
-            "be.meemoo.sipin.sip.validate" => {
-                let status: &str = "SIP_VALIDATED";
-                let res = client.execute(
-                    "UPDATE sipin_sips SET last_event_type=$1, last_event_date=$2, status=$3
-                    WHERE correlation_id=$4", &[
-                        &data.type_field.as_str(),
-                        &data.time,
-                        &status,
-                        &data.correlation_id.as_str(),
-                    ],
-                ).await;
-                match res {
-                    Ok(rows) => match rows {
-                        0 => log::warn!("No rows updated for event {}! correlation_id {} not present?", &data.type_field.as_str(), &data.correlation_id.as_str()),
-                        1 => log::debug!("Rows updated for event {}: {}", &data.type_field.as_str(), rows),
-                        _ => log::warn!("Rows updated for event {}: {}. More then one record with correlation_id {}", &data.type_field.as_str(), rows, &data.correlation_id.as_str()),
-                    },
-                    Err(error) => log::error!("Problem: {:?}", error),
-                };
-            },
-            // Legacy aip (mh-sip) create event
-            "be.meemoo.sipin.aip.create" => {
-                let status: &str = "AIP_CREATED";
-                let pid = split_pid_by_underscore(data.data["pid"].as_str().unwrap());
-                let res = client.execute(
-                    "UPDATE sipin_sips SET last_event_type=$1, last_event_date=$2, status=$3, cp_id=$4, pid=$5
-                    WHERE correlation_id=$6", &[
-                        &data.type_field.as_str(),
-                        &data.time,
-                        &status,
-                        &data.data["cp_id"].as_str(),
-                        &pid,
-                        &data.correlation_id.as_str(),
-                    ],
-                ).await;
-                match res {
-                    Ok(rows) => match rows {
-                        0 => log::warn!("No rows updated for event {}! correlation_id {} not present?", &data.type_field.as_str(), &data.correlation_id.as_str()),
-                        1 => log::debug!("Rows updated for event {}: {}", &data.type_field.as_str(), rows),
-                        _ => log::warn!("Rows updated for event {}: {}. More then one record with correlation_id {}", &data.type_field.as_str(), rows, &data.correlation_id.as_str()),
-                    },
-                    Err(error) => log::error!("Problem: {:?}", error),
-                };
-            },
-            // Sipin mh-sip create event
-            "persistent://public/sipin/mh-sip.create" => {
-                let status: &str = "MH-SIP_CREATED";
-                let pid = split_pid_by_underscore(data.data["pid"].as_str().unwrap());
-                let res = client.execute(
-                    "UPDATE sipin_sips SET last_event_type=$1, last_event_date=$2, status=$3, cp_id=$4, pid=$5, sip_profile=$6
-                    WHERE correlation_id=$7", &[
-                        &data.type_field.as_str(),
-                        &data.time,
-                        &status,
-                        &data.data["cp_id"].as_str(),
-                        &pid,
-                        &data.data["sip_profile"].as_str(),
-                        &data.correlation_id.as_str(),
-                    ],
-                ).await;
-                match res {
-                    Ok(rows) => match rows {
-                        0 => log::warn!("No rows updated for event {}! correlation_id {} not present?", &data.type_field.as_str(), &data.correlation_id.as_str()),
-                        1 => log::debug!("Rows updated for event {}: {}", &data.type_field.as_str(), rows),
-                        _ => log::warn!("Rows updated for event {}: {}. More then one record with correlation_id {}", &data.type_field.as_str(), rows, &data.correlation_id.as_str()),
-                    },
-                    Err(error) => log::error!("Problem: {:?}", error),
-                };
-            },
-            "be.meemoo.sipin.aip.transfer" => {
-                let status: &str = "AIP_DELIVERED_TO_MAM";
-                let res = client.execute(
-                    "UPDATE sipin_sips SET last_event_type=$1, last_event_date=$2, status=$3
-                    WHERE correlation_id=$4", &[
-                        &data.type_field.as_str(),
-                        &data.time,
-                        &status,
-                        &data.correlation_id.as_str(),
-                    ],
-                ).await;
-                match res {
-                    Ok(rows) => match rows {
-                        0 => log::warn!("No rows updated for event {}! correlation_id {} not present?", &data.type_field.as_str(), &data.correlation_id.as_str()),
-                        1 => log::debug!("Rows updated for event {}: {}", &data.type_field.as_str(), rows),
-                        _ => log::warn!("Rows updated for event {}: {}. More then one record with correlation_id {}", &data.type_field.as_str(), rows, &data.correlation_id.as_str()),
-                    },
-                    Err(error) => log::error!("Problem: {:?}", error),
+    // Webhooks to the CPs, delivered from the delivery log
+    let webhook_wake = Arc::new(Notify::new());
+    let webhooks = match config.webhooks_file.is_empty() {
+        true => Arc::new(Webhooks::default()),
+        false => Arc::new(Webhooks::load(&config.webhooks_file)?),
+    };
+    if !webhooks.hooks.is_empty() {
+        let sender = WebhookSender {
+            client: connect_postgres(&config).await?,
+            http: reqwest::Client::builder().timeout(Duration::from_secs(config.webhook_timeout)).build()?,
+            webhooks: webhooks.clone(),
+            wake: webhook_wake.clone(),
+            interval: Duration::from_secs(config.webhook_poll_interval),
+            max_attempts: config.webhook_max_attempts.max(1),
+            backoff: Duration::from_secs(config.webhook_backoff),
+            lease: Duration::from_secs(config.webhook_timeout.saturating_mul(2)),
+        };
+        tokio::spawn(run_webhook_sender(config.clone(), sender));
+    }
+
+    // Status changes committed by the lanes and the admin corrections
+    let sinks = Arc::new(ChangeSinks {
+        broadcast: broadcast::channel(CHANGES_CAPACITY).0,
+        notify_channel: config.postgres_notify_channel.clone(),
+        output_topic: config.pulsar_output_topic.clone(),
+        outbox_wake,
+        webhooks,
+        webhook_wake,
+    });
+
+    // JSON API
+    if config.api_port != 0 {
+        if !config.postgres_notify_channel.is_empty() {
+            tokio::spawn(run_change_listener(config.clone(), sinks.broadcast.clone()));
+        }
+        let state = ApiState {
+            client: client.clone(),
+            admin_client: Arc::new(Mutex::new(connect_postgres(&config).await?)),
+            sinks: sinks.clone(),
+            admin_token: config.admin_token.clone(),
+        };
+        let api_port = config.api_port;
+        tokio::spawn(async move {
+            if let Err(e) = pulsar2db::api::serve(state, api_port).await {
+                log::error!("API server error: {:?}", e);
+            }
+        });
+    }
+
+    // Stalled SIP detection
+    let detector = StalledDetector {
+        client: client.clone(),
+        metrics: metrics.clone(),
+        interval: Duration::from_secs(config.stalled_check_interval),
+        default_timeout: config.stalled_timeout,
+        timeouts: parse_stalled_timeouts(&config.stalled_timeouts)?,
+    };
+    tokio::spawn(detector.run());
+
+    // Periodically log the consumer lag and event latency
+    let lag_log_interval = Duration::from_secs(config.lag_log_interval);
+    let metrics_log = metrics.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(lag_log_interval);
+        loop {
+            interval.tick().await;
+            metrics_log.log_lag();
+        }
+    });
+
+    // Projections configured on top of the built-in sipin_sips state, each
+    // with its own subscription and Postgres connection.
+    if !config.projections_file.is_empty() {
+        for projection in load_projections(&config.projections_file)? {
+            let projection_client = connect_postgres(&config).await?;
+            tokio::spawn(run_projection(projection, config.clone(), subscription_type, projection_client, metrics.clone()));
+        }
+    }
+
+    // Lanes: every lane handles its events in order on its own connection,
+    // while the lanes themselves run concurrently. The lanes outlive the
+    // Pulsar connection, so handled messages can be acknowledged after a
+    // reconnect as well.
+    let workers = config.workers.max(1);
+    log::info!("Starting {} lanes", workers);
+    let cp_mapping_source = CpMappingSource::from_config(&config)?;
+    let context = Arc::new(Context {
+        metrics: metrics.clone(),
+        s3_filter: S3Filter::from_config(&config)?,
+        cp_mapping: RwLock::new(cp_mapping_source.load(&*client.get().await?).await?),
+        sinks: sinks.clone(),
+    });
+    if cp_mapping_source != CpMappingSource::None {
+        let (refresh_client, refresh_context) = (client.clone(), context.clone());
+        let refresh_interval = Duration::from_secs(config.cp_mapping_refresh_interval);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(refresh_interval);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                let mapping = match refresh_client.get().await {
+                    Ok(client) => cp_mapping_source.load(&client).await,
+                    Err(e) => Err(e.into()),
                 };
-            },
-            _ => {
-                log::warn!("Unknown event type: {:#?}", &data.type_field.as_str());
+                match mapping {
+                    Ok(mapping) => *refresh_context.cp_mapping.write().unwrap() = mapping,
+                    Err(e) => log::error!("Could not refresh the CP mapping: {:?}", e),
+                }
+            }
+        });
+    }
+    let (outcome_tx, outcomes) = mpsc::unbounded_channel();
+    let mut senders = Vec::with_capacity(workers);
+    for _ in 0..workers {
+        let (tx, rx) = mpsc::channel(LANE_CAPACITY);
+        let lane_client = connect_postgres(&config).await?;
+        tokio::spawn(run_lane(config.clone(), lane_client, context.clone(), rx, outcome_tx.clone()));
+        senders.push(tx);
+    }
+    drop(outcome_tx);
+    let mut lanes = Lanes { senders, outcomes };
+
+    // Pulsar: (re)connect with exponential backoff, failing over to the next
+    // service URL when a cluster can't be reached.
+    let urls = pulsar_service_urls(&config);
+    log::info!("Pulsar service URLs: {:?}, topics={:?}, subscription_name={}", &urls, &TOPICS, &config.pulsar_subscription_name);
+    let retry = retry_options(&config);
+    let mut backoff = Backoff::new(&retry);
+    let mut counter = 0usize;
+    let mut consumer = loop {
+        let (pulsar, mut consumer) = connect_and_subscribe(&urls, &retry, &mut backoff, &config, &TOPICS, &config.pulsar_subscription_name, subscription_type).await;
+        let received = counter;
+        match consume(&pulsar, &mut consumer, &config, subscription_type, &metrics, &mut lanes, &mut counter).await {
+            Ok(SessionEnd::Stopped) => break consumer,
+            Ok(SessionEnd::Repartitioned) => log::info!("Topics were repartitioned, resubscribing"),
+            // The unacknowledged events are redelivered once the service is
+            // restarted.
+            Ok(SessionEnd::LaneStopped) => return Err(anyhow::anyhow!("a lane stopped, exiting")),
+            Err(e) => {
+                // Only start over from the minimum once the session got
+                // messages through, so a cluster that accepts subscriptions
+                // but fails right after isn't hammered.
+                if counter > received {
+                    backoff.reset();
+                }
+                log::error!("Lost connection to Pulsar: {:?}. Reconnecting in {:?}", e, backoff.delay);
+                backoff.wait().await;
             },
         }
+    };
+
+    // Let the lanes finish the events that were already handed out, and
+    // acknowledge those as well.
+    drop(lanes.senders);
+    while let Some(outcome) = lanes.outcomes.recv().await {
+        match outcome {
+            Outcome::Ack(msg) => consumer.ack(&msg).await?,
+            Outcome::Nack(msg) => consumer.nack(&msg).await?,
+        };
     }
 
     Ok(())
@@ -353,32 +1384,381 @@ async fn main() -> Result<(), anyhow::Error> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use chrono::{DateTime, Utc};
+    use pulsar2db::s3::CpMappingEntry;
+    use pulsar2db::testing::{self, event};
+    use pulsar2db::webhooks::Webhook;
+    use std::collections::HashMap;
+    use tokio_postgres::error::SqlState;
+
+    fn context() -> Context {
+        Context {
+            metrics: Arc::new(Metrics::new().unwrap()),
+            s3_filter: S3Filter::default(),
+            cp_mapping: RwLock::new(CpMapping::default()),
+            sinks: Arc::new(ChangeSinks {
+                broadcast: broadcast::channel(CHANGES_CAPACITY).0,
+                notify_channel: String::new(),
+                output_topic: String::new(),
+                outbox_wake: Arc::new(Notify::new()),
+                webhooks: Arc::new(Webhooks::default()),
+                webhook_wake: Arc::new(Notify::new()),
+            }),
+        }
+    }
+
     #[test]
     fn split_pid_with_underscore() {
         let input_pid: &str = "a1b2c3d4e5_str";
         let result_pid: &str = "a1b2c3d4e5";
-        let result: &str = split_pid_by_underscore(&input_pid);
+        let result: &str = split_pid_by_underscore(input_pid);
         assert_eq!(&result, &result_pid);
     }
     #[test]
     fn split_pid_without_underscore() {
         let input_pid: &str = "a1b2c3d4e5";
         let result_pid: &str = "a1b2c3d4e5";
-        let result: &str = split_pid_by_underscore(&input_pid);
+        let result: &str = split_pid_by_underscore(input_pid);
         assert_eq!(&result, &result_pid);
     }
     #[test]
     fn split_pid_with_hyphen() {
         let input_pid: &str = "a1b2c3d4e5-str";
         let result_pid: &str = "a1b2c3d4e5-str";
-        let result: &str = split_pid_by_underscore(&input_pid);
+        let result: &str = split_pid_by_underscore(input_pid);
         assert_eq!(&result, &result_pid);
     }
     #[test]
+    fn lane_for_same_correlation_id() {
+        let lane = lane_for("e1c4b3d2-correlation-id", 8);
+        assert!(lane < 8);
+        assert_eq!(lane_for("e1c4b3d2-correlation-id", 8), lane);
+    }
+    #[test]
+    fn lane_for_single_lane() {
+        assert_eq!(lane_for("e1c4b3d2-correlation-id", 1), 0);
+    }
+    #[test]
+    fn update_statement_with_columns() {
+        let statement = update_statement(&["cp_id", "pid"], &[]);
+        assert!(statement.contains("cp_id = COALESCE($5, cp_id)"));
+        assert!(statement.contains("pid = COALESCE($6, pid)"));
+        assert!(statement.contains("WHERE correlation_id = $1 AND row_id = old_row_id"));
+        assert!(statement.contains("RETURNING old_status, old_event_date, status, first_event_date, cp_id, pid,"));
+    }
+    #[test]
+    fn update_statement_with_defaults() {
+        let statement = update_statement(&["bag_name"], &["cp_id"]);
+        assert!(statement.contains("bag_name = COALESCE($5, bag_name)"));
+        assert!(statement.contains("cp_id = COALESCE(cp_id, $6)"));
+    }
+    #[test]
+    fn update_statement_without_columns() {
+        let statement = update_statement(&[], &[]);
+        assert!(statement.contains("stalled = false\n"));
+    }
+    #[test]
+    fn insert_statement_with_columns() {
+        let statement = insert_statement(&["cp_id", "pid"]);
+        assert!(statement.contains("status, cp_id, pid)"));
+        assert!(statement.contains("VALUES ($1, $2, $3, $2, $4, $5, $6)"));
+    }
+    #[test]
+    fn update_statement_guards_on_event_date() {
+        let statement = update_statement(&[], &[]);
+        assert!(statement.contains("first_event_date = LEAST(first_event_date, $2)"));
+        assert!(statement.contains("last_event_type = CASE WHEN last_event_date <= $2 THEN $3 ELSE last_event_type END"));
+        assert!(statement.contains("status = CASE WHEN last_event_date <= $2 THEN $4 ELSE status END"));
+        assert!(statement.contains("last_event_date = GREATEST(last_event_date, $2)"));
+    }
+    #[tokio::test]
+    #[ignore = "needs PULSAR2DB_TEST_POSTGRES"]
+    async fn update_statement_keeps_newer_status() {
+        let client = testing::database("update_statement_keeps_newer_status", testing::DDL).await;
+        let time = |time: &str| time.parse::<DateTime<Utc>>().unwrap();
+        let insert = insert_statement(&["pid"]);
+        let update = update_statement(&["pid"], &[]);
+        let no_pid: Option<&str> = None;
+        client.execute(insert.as_str(), &[&"abc", &time("2024-05-20T10:00:00Z"), &"be.meemoo.sipin.bag.validate", &"BAG_VALIDATED", &no_pid]).await.unwrap();
+
+        // An older event fills in its columns, but keeps the status.
+        let row = client.query_one(update.as_str(), &[&"abc", &time("2024-05-20T09:00:00Z"), &"be.meemoo.sipin.bag.unzip", &"BAG_UNZIPPED", &Some("a1b2c3d4e5")]).await.unwrap();
+        assert_eq!(row.get::<_, Option<String>>("old_status").as_deref(), Some("BAG_VALIDATED"));
+        assert_eq!(row.get::<_, String>("status"), "BAG_VALIDATED");
+        assert_eq!(row.get::<_, DateTime<Utc>>("first_event_date"), time("2024-05-20T09:00:00Z"));
+        assert_eq!(row.get::<_, Option<String>>("pid").as_deref(), Some("a1b2c3d4e5"));
+        let row = client.query_one("SELECT last_event_type, last_event_date FROM sipin_sips WHERE correlation_id = 'abc'", &[]).await.unwrap();
+        assert_eq!(row.get::<_, String>("last_event_type"), "be.meemoo.sipin.bag.validate");
+        assert_eq!(row.get::<_, DateTime<Utc>>("last_event_date"), time("2024-05-20T10:00:00Z"));
+
+        // A newer event sets the status, and keeps the columns it has no value for.
+        let row = client.query_one(update.as_str(), &[&"abc", &time("2024-05-20T11:00:00Z"), &"be.meemoo.sipin.sip.validate", &"SIP_VALIDATED", &no_pid]).await.unwrap();
+        assert_eq!(row.get::<_, String>("status"), "SIP_VALIDATED");
+        assert_eq!(row.get::<_, Option<String>>("pid").as_deref(), Some("a1b2c3d4e5"));
+        let row = client.query_one("SELECT last_event_type, last_event_date FROM sipin_sips WHERE correlation_id = 'abc'", &[]).await.unwrap();
+        assert_eq!(row.get::<_, String>("last_event_type"), "be.meemoo.sipin.sip.validate");
+        assert_eq!(row.get::<_, DateTime<Utc>>("last_event_date"), time("2024-05-20T11:00:00Z"));
+    }
+    #[test]
+    fn md5_from_etag_with_quotes() {
+        let result = md5_from_etag("\"1b2cf535f27731c974343645a3985328\"");
+        assert_eq!(result, Some("1b2cf535f27731c974343645a3985328"));
+    }
+    #[test]
+    fn md5_from_etag_multipart() {
+        assert_eq!(md5_from_etag("1b2cf535f27731c974343645a3985328-12"), None);
+    }
+    #[test]
+    fn s3_records_all_records() {
+        let data = serde_json::json!({"s3_message": {"Records": [
+            {"s3": {
+                "domain": {"s3-endpoint": "https://s3.example.com"},
+                "bucket": {"name": "ingest"},
+                "object": {"key": "OR-1/my+bag%281%29.bag.zip", "size": 1024, "eTag": "\"0123456789abcdef0123456789abcdef\""},
+            }},
+            {"s3": {
+                "bucket": {"name": "ingest"},
+                "object": {"key": "OR-1/other.bag.zip", "eTag": "0123456789abcdef0123456789abcdef-2"},
+            }},
+        ]}});
+        let records = s3_records(&data);
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].host, Some("https://s3.example.com"));
+        assert_eq!(records[0].key.as_deref(), Some("OR-1/my bag(1).bag.zip"));
+        assert_eq!(records[0].size, Some(1024));
+        assert_eq!(records[0].md5, Some("0123456789abcdef0123456789abcdef"));
+        assert_eq!(records[1].host, None);
+        assert_eq!(records[1].md5, None);
+    }
+    #[test]
+    fn s3_records_without_records() {
+        assert!(s3_records(&serde_json::json!({})).is_empty());
+    }
+    #[test]
+    fn record_correlation_id_suffix() {
+        assert_eq!(record_correlation_id("abc", 2), "abc-2");
+    }
+    #[tokio::test]
+    #[ignore = "needs PULSAR2DB_TEST_POSTGRES"]
+    async fn new_pipeline_ends_delivered() {
+        let mut client = testing::database("new_pipeline_ends_delivered", testing::DDL).await;
+        let context = context();
+        let events = [
+            (S3_OBJECT_CREATE, serde_json::json!({"s3_message": {"Records": [
+                {"s3": {"bucket": {"name": "ingest"}, "object": {"key": "OR-1/bag.zip"}}},
+            ]}})),
+            ("persistent://public/sipin/bag.transfer", serde_json::json!({})),
+            ("persistent://public/sipin/bag.unzip", serde_json::json!({})),
+            ("persistent://public/sipin/bag.validate", serde_json::json!({})),
+            ("persistent://public/sipin/sip.validate.xsd", serde_json::json!({})),
+            ("persistent://public/sipin/sip.loadgraph", serde_json::json!({})),
+            ("persistent://public/sipin/sip.validate.shacl", serde_json::json!({})),
+            ("persistent://public/sipin/mh-sip.create", serde_json::json!({"cp_id": "OR-1", "pid": "a1b2c3d4e5"})),
+            ("persistent://public/sipin/mh-sip.transfer", serde_json::json!({})),
+        ];
+        for (minute, (type_field, data)) in events.into_iter().enumerate() {
+            let time = format!("2024-05-20T10:{:02}:00Z", minute);
+            assert!(handle_event(&mut client, &context, &event("abc", type_field, &time, data)).await.unwrap());
+        }
+        let detector = StalledDetector {
+            client: Arc::new(testing::shared_connection("new_pipeline_ends_delivered").await),
+            metrics: context.metrics.clone(),
+            interval: Duration::from_secs(60),
+            default_timeout: 0,
+            timeouts: Default::default(),
+        };
+        detector.scan().await.unwrap();
+        let row = client.query_one("SELECT status, stalled FROM sipin_sips WHERE correlation_id = 'abc'", &[]).await.unwrap();
+        assert_eq!(row.get::<_, &str>(0), "AIP_DELIVERED_TO_MAM");
+        assert!(!row.get::<_, bool>(1));
+    }
+    #[tokio::test]
+    #[ignore = "needs PULSAR2DB_TEST_POSTGRES"]
+    async fn data_errors_are_not_transient() {
+        let mut client = testing::database("data_errors_are_not_transient", testing::DDL).await;
+        let context = context();
+        let create = |correlation_id: &str, pid: &str| event(correlation_id, "persistent://public/sipin/mh-sip.create", "2024-05-20T10:00:00Z", serde_json::json!({
+            "cp_id": "OR-1",
+            "pid": pid,
+        }));
+        handle_event(&mut client, &context, &create("main", "a1b2c3d4e5")).await.unwrap();
+        // A collateral splits to the pid of its main SIP.
+        let error = handle_event(&mut client, &context, &create("collateral", "a1b2c3d4e5_srt")).await.unwrap_err();
+        let error: &tokio_postgres::Error = error.downcast_ref().unwrap();
+        assert_eq!(error.code(), Some(&SqlState::UNIQUE_VIOLATION));
+        assert!(!is_transient(error));
+        let error = handle_event(&mut client, &context, &create("long", "a1b2c3d4e5f6")).await.unwrap_err();
+        let error: &tokio_postgres::Error = error.downcast_ref().unwrap();
+        assert_eq!(error.code(), Some(&SqlState::STRING_DATA_RIGHT_TRUNCATION));
+        assert!(!is_transient(error));
+    }
+    #[tokio::test]
+    #[ignore = "needs PULSAR2DB_TEST_POSTGRES"]
+    async fn incomplete_events_fail() {
+        let mut client = testing::database("incomplete_events_fail", testing::DDL).await;
+        let context = context();
+        for type_field in ["be.meemoo.sipin.sip.create", "be.meemoo.sipin.aip.create", "persistent://public/sipin/mh-sip.create"] {
+            let error = handle_event(&mut client, &context, &event("abc", type_field, "2024-05-20T10:00:00Z", serde_json::json!({}))).await.unwrap_err();
+            assert!(error.downcast_ref::<tokio_postgres::Error>().is_none());
+        }
+    }
+    #[tokio::test]
+    #[ignore = "needs PULSAR2DB_TEST_POSTGRES"]
+    async fn failed_stages_call_the_webhook() {
+        let mut client = testing::database("failed_stages_call_the_webhook", testing::DDL).await;
+        let hook = Webhook {
+            cp_id: String::from("OR-1"),
+            url: String::from("http://localhost/hook"),
+            secret: String::from("secret"),
+        };
+        let context = Context {
+            sinks: Arc::new(ChangeSinks {
+                webhooks: Arc::new(Webhooks { hooks: HashMap::from([(hook.cp_id.clone(), hook)]) }),
+                ..Arc::into_inner(context().sinks).unwrap()
+            }),
+            ..context()
+        };
+        handle_event(&mut client, &context, &event("abc", "persistent://public/sipin/bag.validate", "2024-05-20T10:00:00Z", serde_json::json!({}))).await.unwrap();
+        handle_event(&mut client, &context, &event("abc", "persistent://public/sipin/mh-sip.create", "2024-05-20T10:01:00Z", serde_json::json!({"cp_id": "OR-1", "pid": "a1b2c3d4e5"}))).await.unwrap();
+        handle_event(&mut client, &context, &event("abc", "persistent://public/sipin/sip.validate.xsd", "2024-05-20T10:02:00Z", serde_json::json!({}))).await.unwrap();
+        let failed = CloudEvent {
+            outcome: String::from("failed"),
+            ..event("abc", "persistent://public/sipin/sip.loadgraph", "2024-05-20T10:03:00Z", serde_json::json!({}))
+        };
+        // Redelivered.
+        handle_event(&mut client, &context, &failed).await.unwrap();
+        handle_event(&mut client, &context, &failed).await.unwrap();
+        let payloads: Vec<serde_json::Value> = client.query("SELECT payload FROM sipin_webhook_deliveries", &[]).await.unwrap()
+            .iter().map(|row| row.get(0)).collect();
+        assert_eq!(payloads.len(), 1);
+        assert_eq!(payloads[0]["stage"], "loadgraph");
+        assert_eq!(payloads[0]["outcome"], "failed");
+        assert_eq!(payloads[0]["new_status"], "MH-SIP_CREATED");
+        assert_eq!(payloads[0]["old_status"], "MH-SIP_CREATED");
+    }
+    #[tokio::test]
+    #[ignore = "needs PULSAR2DB_TEST_POSTGRES"]
+    async fn s3_records_become_sips() {
+        let mut client = testing::database("s3_records_become_sips", testing::DDL).await;
+        let context = context();
+        let notification = event("abc", S3_OBJECT_CREATE, "2024-05-20T09:00:00Z", serde_json::json!({"s3_message": {"Records": [
+            {"s3": {"bucket": {"name": "ingest"}, "object": {"key": "OR-1/first.bag.zip", "size": 1024}}},
+            {"s3": {"bucket": {"name": "ingest"}, "object": {"key": "OR-1/second.bag.zip", "size": 2048}}},
+        ]}}));
+        assert!(handle_event(&mut client, &context, &notification).await.unwrap());
+        let rows: Vec<String> = client.query(
+            "SELECT concat_ws(' ', correlation_id, bag_name, bag_filesize, resolution) FROM sipin_sips ORDER BY correlation_id", &[],
+        ).await.unwrap().iter().map(|row| row.get(0)).collect();
+        assert_eq!(rows, ["abc first.bag.zip 1024", "abc-1 second.bag.zip 2048 ignored"]);
+    }
+    #[tokio::test]
+    #[ignore = "needs PULSAR2DB_TEST_POSTGRES"]
+    async fn s3_records_first_accepted_keeps_correlation_id() {
+        let mut client = testing::database("s3_records_first_accepted_keeps_correlation_id", testing::DDL).await;
+        let context = Context {
+            s3_filter: S3Filter { key_include: Some(regex::Regex::new(r"\.bag\.zip$").unwrap()), ..S3Filter::default() },
+            ..context()
+        };
+        let notification = event("abc", S3_OBJECT_CREATE, "2024-05-20T09:00:00Z", serde_json::json!({"s3_message": {"Records": [
+            {"s3": {"bucket": {"name": "ingest"}, "object": {"key": "OR-1/first.xml"}}},
+            {"s3": {"bucket": {"name": "ingest"}, "object": {"key": "OR-1/second.bag.zip"}}},
+            {"s3": {"bucket": {"name": "ingest"}, "object": {"key": "OR-1/third.bag.zip"}}},
+        ]}}));
+        assert!(handle_event(&mut client, &context, &notification).await.unwrap());
+        let rows: Vec<String> = client.query(
+            "SELECT concat_ws(' ', correlation_id, bag_name, resolution) FROM sipin_sips ORDER BY correlation_id", &[],
+        ).await.unwrap().iter().map(|row| row.get(0)).collect();
+        assert_eq!(rows, ["abc second.bag.zip", "abc-2 third.bag.zip ignored"]);
+    }
+    #[tokio::test]
+    #[ignore = "needs PULSAR2DB_TEST_POSTGRES"]
+    async fn mapped_cp_id_does_not_overwrite() {
+        let mut client = testing::database("mapped_cp_id_does_not_overwrite", testing::DDL).await;
+        let context = Context {
+            cp_mapping: RwLock::new(CpMapping { entries: vec![CpMappingEntry {
+                bucket: String::from("ingest"),
+                key_prefix: String::new(),
+                cp_id: String::from("OR-mapped"),
+            }] }),
+            ..context()
+        };
+        let notification = |correlation_id: &str| event(correlation_id, S3_OBJECT_CREATE, "2024-05-20T09:00:00Z", serde_json::json!({"s3_message": {"Records": [
+            {"s3": {"bucket": {"name": "ingest"}, "object": {"key": "OR-1/bag.zip"}}},
+        ]}}));
+        let create = |correlation_id: &str| event(correlation_id, "persistent://public/sipin/mh-sip.create", "2024-05-20T10:00:00Z", serde_json::json!({
+            "cp_id": "OR-create",
+            "pid": format!("{}pid", correlation_id),
+        }));
+        // The S3 event arrives after mh-sip.create
+        handle_event(&mut client, &context, &create("late")).await.unwrap();
+        handle_event(&mut client, &context, &notification("late")).await.unwrap();
+        // In order
+        handle_event(&mut client, &context, &notification("early")).await.unwrap();
+        assert_eq!(client.query_one("SELECT cp_id FROM sipin_sips WHERE correlation_id = 'early'", &[]).await.unwrap().get::<_, String>(0), "OR-mapped");
+        handle_event(&mut client, &context, &create("early")).await.unwrap();
+        let rows: Vec<String> = client.query(
+            "SELECT concat_ws(' ', correlation_id, cp_id) FROM sipin_sips ORDER BY correlation_id", &[],
+        ).await.unwrap().iter().map(|row| row.get(0)).collect();
+        assert_eq!(rows, ["early OR-create", "late OR-create"]);
+    }
+    #[tokio::test]
+    #[ignore = "needs PULSAR2DB_TEST_POSTGRES"]
+    async fn concurrent_events_insert_one_row() {
+        let name = "concurrent_events_insert_one_row";
+        let mut client = testing::partitioned_database(name).await;
+        let mut other = testing::connection(name).await;
+        let context = context();
+        for i in 0..20 {
+            let correlation_id = format!("sip{}", i);
+            let unzip = event(&correlation_id, "be.meemoo.sipin.bag.unzip", "2024-05-20T09:00:00Z", serde_json::json!({}));
+            let validate = event(&correlation_id, "be.meemoo.sipin.bag.validate", "2024-05-20T09:05:00Z", serde_json::json!({}));
+            let (unzipped, validated) = tokio::join!(
+                handle_event(&mut client, &context, &unzip),
+                handle_event(&mut other, &context, &validate),
+            );
+            unzipped.unwrap();
+            validated.unwrap();
+        }
+        let rows = client.query("SELECT correlation_id, status FROM sipin_sips ORDER BY correlation_id", &[]).await.unwrap();
+        assert_eq!(rows.len(), 20);
+        assert!(rows.iter().all(|row| row.get::<_, String>("status") == "BAG_VALIDATED"));
+    }
+    #[tokio::test]
+    #[ignore = "needs PULSAR2DB_TEST_POSTGRES"]
+    async fn checksum_mismatch_counted_once() {
+        let mut client = testing::database("checksum_mismatch_counted_once", testing::DDL).await;
+        let context = context();
+        let manifest = "1b2cf535f27731c974343645a3985328";
+        let create = |correlation_id: &str, sidecar: &str| event(correlation_id, "be.meemoo.sipin.sip.create", "2024-05-20T09:00:00Z", serde_json::json!({
+            "path": format!("/in/{}.bag.zip", correlation_id),
+            "md5_hash_essence_manifest": manifest,
+            "md5_hash_essence_sidecar": sidecar,
+        }));
+        // Case-insensitive
+        handle_event(&mut client, &context, &create("abc", "1B2CF535F27731C974343645A3985328")).await.unwrap();
+        assert_eq!(context.metrics.checksum_mismatches.get(), 0);
+        // Redelivered
+        handle_event(&mut client, &context, &create("def", "00000000000000000000000000000000")).await.unwrap();
+        handle_event(&mut client, &context, &create("def", "00000000000000000000000000000000")).await.unwrap();
+        assert_eq!(context.metrics.checksum_mismatches.get(), 1);
+    }
+    #[test]
+    fn stage_for_new_and_legacy_events() {
+        assert_eq!(stage_for("persistent://public/sipin/sip.loadgraph"), Some("loadgraph"));
+        assert_eq!(stage_for("be.meemoo.sipin.aip.create"), Some("mh_sip_create"));
+        assert_eq!(stage_for("persistent://public/default/be.meemoo.sipin.bag.transfer"), Some("transfer"));
+        assert_eq!(stage_for("be.meemoo.sipin.unknown"), None);
+    }
+    #[test]
     fn filename_from_path_with_filename() {
         let input_path = Some("/home/username/files/directory/filename-123.bag.zip");
         let expected_filename = "filename-123.bag.zip";
         let result = filename_from_path(input_path).unwrap();
         assert_eq!(&result, &expected_filename);
     }
+    #[test]
+    fn filename_from_path_without_filename() {
+        assert_eq!(filename_from_path(None), None);
+        assert_eq!(filename_from_path(Some("/")), None);
+    }
 }
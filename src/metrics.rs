@@ -0,0 +1,251 @@
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use axum::{extract::State, routing::get, Router};
+use chrono::{DateTime, TimeZone, Utc};
+use prometheus::core::Collector;
+use prometheus::{
+    Encoder, GaugeVec, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+use crate::CloudEvent;
+
+/// All Prometheus metrics exposed by pulsar2db.
+///
+/// A single instance is created at startup and shared (via an `Arc`)
+/// between the consumer loop and the background tasks.
+pub struct Metrics {
+    pub registry: Registry,
+    /// Number of SIPs currently flagged as stalled, per status.
+    pub stalled_sips: IntGaugeVec,
+    /// Number of SIPs that were newly flagged as stalled, per status.
+    pub stalled_sips_detected: IntCounterVec,
+    /// Number of partitions, per topic. 0 for non-partitioned topics.
+    pub topic_partitions: IntGaugeVec,
+    /// Number of messages received, per topic and partition.
+    pub messages_received: IntCounterVec,
+    /// Number of messages whose key differs from their correlation_id on a
+    /// Key_Shared subscription, per topic.
+    pub key_mismatches: IntCounterVec,
+    /// Seconds between the publish time of the last received message and
+    /// the moment it was received, per topic and partition.
+    pub consumer_lag_seconds: GaugeVec,
+    /// Number of SIPs for which the essence checksums in the manifest and
+    /// sidecar differ.
+    pub checksum_mismatches: IntCounter,
+    /// Seconds between `CloudEvent.time` and the moment the state was
+    /// committed, per event type.
+    pub event_latency_seconds: HistogramVec,
+    /// Number of objects in S3 notifications that were ignored by the S3
+    /// filter, per bucket.
+    pub s3_objects_ignored: IntCounterVec,
+    /// Number of events that were acknowledged without being handled, since
+    /// handling them failed in a way that doesn't go away, per event type.
+    pub events_skipped: IntCounterVec,
+    /// Number of messages that were acknowledged without being handled, since
+    /// they could not be deserialized to a CloudEvent, per topic.
+    pub undeserializable_messages: IntCounterVec,
+    /// The event latencies observed since they were last logged, see
+    /// `log_lag`.
+    recent_latencies: Mutex<Vec<f64>>,
+}
+
+impl Metrics {
+    pub fn new() -> Result<Metrics, prometheus::Error> {
+        let registry = Registry::new_custom(Some(String::from("pulsar2db")), None)?;
+        let stalled_sips = IntGaugeVec::new(
+            Opts::new("stalled_sips", "Number of SIPs currently flagged as stalled"),
+            &["status"],
+        )?;
+        let stalled_sips_detected = IntCounterVec::new(
+            Opts::new("stalled_sips_detected_total", "Number of SIPs newly flagged as stalled"),
+            &["status"],
+        )?;
+        let topic_partitions = IntGaugeVec::new(
+            Opts::new("topic_partitions", "Number of partitions of the topic"),
+            &["topic"],
+        )?;
+        let messages_received = IntCounterVec::new(
+            Opts::new("messages_received_total", "Number of messages received"),
+            &["topic", "partition"],
+        )?;
+        let key_mismatches = IntCounterVec::new(
+            Opts::new("key_mismatches_total", "Number of messages not keyed on their correlation_id"),
+            &["topic"],
+        )?;
+        let consumer_lag_seconds = GaugeVec::new(
+            Opts::new("consumer_lag_seconds", "Seconds between publishing and receiving the last message"),
+            &["topic", "partition"],
+        )?;
+        let checksum_mismatches = IntCounter::new(
+            "checksum_mismatches_total", "Number of SIPs with differing manifest and sidecar checksums",
+        )?;
+        let event_latency_seconds = HistogramVec::new(
+            HistogramOpts::new("event_latency_seconds", "Seconds between the event time and committing the state")
+                .buckets(vec![0.1, 0.5, 1.0, 5.0, 15.0, 60.0, 300.0, 900.0, 3600.0]),
+            &["event_type"],
+        )?;
+        let s3_objects_ignored = IntCounterVec::new(
+            Opts::new("s3_objects_ignored_total", "Number of S3 objects that did not pass the S3 filter"),
+            &["bucket"],
+        )?;
+        let events_skipped = IntCounterVec::new(
+            Opts::new("events_skipped_total", "Number of events skipped after an error that retrying doesn't fix"),
+            &["event_type"],
+        )?;
+        registry.register(Box::new(stalled_sips.clone()))?;
+        registry.register(Box::new(stalled_sips_detected.clone()))?;
+        registry.register(Box::new(topic_partitions.clone()))?;
+        registry.register(Box::new(messages_received.clone()))?;
+        registry.register(Box::new(key_mismatches.clone()))?;
+        registry.register(Box::new(consumer_lag_seconds.clone()))?;
+        registry.register(Box::new(checksum_mismatches.clone()))?;
+        registry.register(Box::new(event_latency_seconds.clone()))?;
+        registry.register(Box::new(s3_objects_ignored.clone()))?;
+        let undeserializable_messages = IntCounterVec::new(
+            Opts::new("undeserializable_messages_total", "Number of messages skipped since they could not be deserialized"),
+            &["topic"],
+        )?;
+        registry.register(Box::new(events_skipped.clone()))?;
+        registry.register(Box::new(undeserializable_messages.clone()))?;
+        Ok(Metrics {
+            registry,
+            stalled_sips,
+            stalled_sips_detected,
+            topic_partitions,
+            messages_received,
+            key_mismatches,
+            consumer_lag_seconds,
+            checksum_mismatches,
+            event_latency_seconds,
+            s3_objects_ignored,
+            events_skipped,
+            undeserializable_messages,
+            recent_latencies: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// Record the receipt of a message published (in milliseconds since the
+    /// epoch) at `publish_time` on a partition of `topic`. Messages on
+    /// non-partitioned topics are recorded with partition `-1`.
+    pub fn observe_message(&self, topic: &str, partition: Option<u32>, publish_time: u64) {
+        let partition = partition.map_or(String::from("-1"), |p| p.to_string());
+        self.messages_received.with_label_values(&[topic, &partition]).inc();
+        if let Some(published) = Utc.timestamp_millis_opt(publish_time as i64).single() {
+            self.consumer_lag_seconds
+                .with_label_values(&[topic, &partition])
+                .set(seconds_since(published));
+        }
+    }
+
+    /// Record the delay between the event time and now, to be called right
+    /// after the state for the event was committed.
+    pub fn observe_event_latency(&self, event: &CloudEvent) {
+        let latency = seconds_since(event.time);
+        self.event_latency_seconds
+            .with_label_values(&[event.type_field.as_str()])
+            .observe(latency);
+        self.recent_latencies.lock().unwrap().push(latency);
+    }
+
+    /// The number, median and maximum of the event latencies observed since
+    /// the last call, or `None` if there were none.
+    fn take_recent_latency(&self) -> Option<(usize, f64, f64)> {
+        let mut latencies = std::mem::take(&mut *self.recent_latencies.lock().unwrap());
+        if latencies.is_empty() {
+            return None;
+        }
+        latencies.sort_by(f64::total_cmp);
+        Some((latencies.len(), latencies[latencies.len() / 2], latencies[latencies.len() - 1]))
+    }
+
+    /// Log the current consumer lag for every topic and partition, and the
+    /// latency of the events handled since the last call.
+    pub fn log_lag(&self) {
+        match self.take_recent_latency() {
+            Some((count, p50, max)) => log::info!("Event latency over the last {} events: p50 {:.1}s, max {:.1}s", count, p50, max),
+            None => log::info!("Event latency: no events handled"),
+        }
+        for family in self.consumer_lag_seconds.collect() {
+            for metric in family.get_metric() {
+                let label = |name: &str| metric.get_label().iter()
+                    .find(|label| label.get_name() == name)
+                    .map(|label| label.get_value())
+                    .unwrap_or_default();
+                log::info!("Consumer lag for topic {} partition {}: {:.1}s", label("topic"), label("partition"), metric.get_gauge().get_value());
+            }
+        }
+    }
+
+    /// Render all registered metrics in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut buffer = Vec::new();
+        let encoder = TextEncoder::new();
+        if let Err(error) = encoder.encode(&self.registry.gather(), &mut buffer) {
+            log::error!("could not encode metrics: {:?}", error);
+        }
+        String::from_utf8(buffer).unwrap_or_default()
+    }
+}
+
+/// Seconds elapsed since `time`. Negative if `time` is in the future.
+fn seconds_since(time: DateTime<Utc>) -> f64 {
+    (Utc::now() - time).num_milliseconds() as f64 / 1000.0
+}
+
+async fn metrics_handler(State(metrics): State<Arc<Metrics>>) -> String {
+    metrics.render()
+}
+
+/// Serve the metrics on `/metrics` on the given port.
+pub async fn serve(metrics: Arc<Metrics>, port: u16) -> Result<(), anyhow::Error> {
+    let app = Router::new()
+        .route("/metrics", get(metrics_handler))
+        .with_state(metrics);
+    let addr = SocketAddr::from(([0, 0, 0, 0], port));
+    log::info!("Serving metrics on {}", addr);
+    axum::Server::bind(&addr)
+        .serve(app.into_make_service())
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::event;
+
+    #[test]
+    fn observe_message_records_lag() {
+        let metrics = Metrics::new().unwrap();
+        let published = (Utc::now() - chrono::Duration::seconds(30)).timestamp_millis() as u64;
+        metrics.observe_message("public/sipin/bag.unzip", Some(2), published);
+        metrics.observe_message("public/sipin/bag.unzip", None, published);
+        assert_eq!(metrics.messages_received.with_label_values(&["public/sipin/bag.unzip", "2"]).get(), 1);
+        assert_eq!(metrics.messages_received.with_label_values(&["public/sipin/bag.unzip", "-1"]).get(), 1);
+        let lag = metrics.consumer_lag_seconds.with_label_values(&["public/sipin/bag.unzip", "2"]).get();
+        assert!((30.0..35.0).contains(&lag), "lag {}", lag);
+        assert!(metrics.render().contains("pulsar2db_consumer_lag_seconds{partition=\"2\",topic=\"public/sipin/bag.unzip\"}"));
+    }
+    #[test]
+    fn observe_event_latency_records_delay() {
+        let metrics = Metrics::new().unwrap();
+        let event = event("abc", "be.meemoo.sipin.bag.unzip", &(Utc::now() - chrono::Duration::seconds(120)).to_rfc3339(), serde_json::json!({}));
+        metrics.observe_event_latency(&event);
+        let histogram = metrics.event_latency_seconds.with_label_values(&["be.meemoo.sipin.bag.unzip"]);
+        assert_eq!(histogram.get_sample_count(), 1);
+        assert!((120.0..125.0).contains(&histogram.get_sample_sum()));
+    }
+    #[test]
+    fn recent_latency_since_last_taken() {
+        let metrics = Metrics::new().unwrap();
+        for seconds in [60, 10, 30] {
+            let event = event("abc", "be.meemoo.sipin.bag.unzip", &(Utc::now() - chrono::Duration::seconds(seconds)).to_rfc3339(), serde_json::json!({}));
+            metrics.observe_event_latency(&event);
+        }
+        let (count, p50, max) = metrics.take_recent_latency().unwrap();
+        assert_eq!(count, 3);
+        assert!((30.0..35.0).contains(&p50), "p50 {}", p50);
+        assert!((60.0..65.0).contains(&max), "max {}", max);
+        assert_eq!(metrics.take_recent_latency(), None);
+    }
+}
@@ -0,0 +1,272 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use pulsar::{Producer, Pulsar, TokioExecutor};
+use tokio::sync::Notify;
+use tokio_postgres::{Client, Transaction};
+use crate::CloudEvent;
+
+/// Publishes outbound events. Implemented for Pulsar, and by a stand-in in
+/// the tests.
+pub trait Publisher {
+    /// Publish an event on a topic, returning once the broker acknowledged
+    /// it.
+    fn publish(&mut self, topic: &str, event: CloudEvent) -> impl Future<Output = Result<(), anyhow::Error>> + Send;
+}
+
+/// Publishes on Pulsar, with one producer per topic.
+pub struct PulsarPublisher {
+    pulsar: Pulsar<TokioExecutor>,
+    producers: HashMap<String, Producer<TokioExecutor>>,
+}
+
+impl PulsarPublisher {
+    pub fn new(pulsar: Pulsar<TokioExecutor>) -> PulsarPublisher {
+        PulsarPublisher { pulsar, producers: HashMap::new() }
+    }
+}
+
+impl Publisher for PulsarPublisher {
+    async fn publish(&mut self, topic: &str, event: CloudEvent) -> Result<(), anyhow::Error> {
+        if !self.producers.contains_key(topic) {
+            // The broker names the producer: a name of our own would be
+            // taken by the replica that registered it first.
+            let producer = self.pulsar.producer()
+                .with_topic(topic)
+                .build()
+                .await?;
+            self.producers.insert(topic.to_string(), producer);
+        }
+        let producer = self.producers.get_mut(topic).unwrap();
+        producer.send(event).await?.await?;
+        Ok(())
+    }
+}
+
+/// An event waiting in the outbox.
+#[derive(Debug)]
+pub struct OutboxEntry {
+    pub id: i64,
+    pub topic: String,
+    pub event: CloudEvent,
+}
+
+/// Add an event to the outbox, as part of the transaction that caused it.
+pub async fn enqueue(transaction: &Transaction<'_>, topic: &str, event: &CloudEvent) -> Result<u64, tokio_postgres::Error> {
+    let payload = serde_json::to_value(event).unwrap_or_default();
+    transaction.execute(
+        "INSERT INTO sipin_outbox (topic, payload) VALUES ($1, $2)",
+        &[&topic, &payload],
+    ).await
+}
+
+/// Publish the entries in order, stopping at the first failure. Returns the
+/// ids of the published entries, and the error if there was one.
+pub async fn publish_entries<P: Publisher>(
+    publisher: &mut P,
+    entries: Vec<OutboxEntry>,
+) -> (Vec<i64>, Option<anyhow::Error>) {
+    let mut published = Vec::with_capacity(entries.len());
+    for entry in entries {
+        if let Err(error) = publisher.publish(&entry.topic, entry.event).await {
+            return (published, Some(error));
+        }
+        published.push(entry.id);
+    }
+    (published, None)
+}
+
+/// Publishes the events in `sipin_outbox` and marks them as published.
+///
+/// Events are only marked as published once the broker acknowledged them, so
+/// none get lost; a crash in between means they are published again. The ids
+/// of the CloudEvents allow consumers to drop such duplicates. Published
+/// events are kept for `retention` before they are deleted.
+///
+/// Events are published in the order of their ids. With several instances,
+/// an advisory lock makes sure only one of them relays at a time.
+pub struct OutboxRelay {
+    pub client: Client,
+    pub wake: Arc<Notify>,
+    pub interval: Duration,
+    pub batch_size: i64,
+    pub retention: Duration,
+}
+
+impl OutboxRelay {
+    /// Wait until new events were committed, or the interval passed.
+    pub async fn wait(&self) {
+        tokio::select! {
+            _ = self.wake.notified() => (),
+            _ = tokio::time::sleep(self.interval) => (),
+        }
+    }
+
+    /// Publish a batch of events. Returns the number of published events, or
+    /// the error that stopped publishing; events published before that are
+    /// marked as such regardless. Publishes nothing while another instance
+    /// is relaying.
+    pub async fn relay<P: Publisher>(&mut self, publisher: &mut P) -> Result<usize, anyhow::Error> {
+        let transaction = self.client.transaction().await?;
+        let relaying: bool = transaction.query_one(
+            "SELECT pg_try_advisory_xact_lock(hashtext('pulsar2db.outbox'))", &[],
+        ).await?.get(0);
+        if !relaying {
+            log::debug!("Another instance is relaying the outbox");
+            return Ok(0);
+        }
+        let rows = transaction.query(
+            "SELECT id, topic, payload FROM sipin_outbox
+            WHERE published_at IS NULL
+            ORDER BY id
+            LIMIT $1", &[&self.batch_size],
+        ).await?;
+        let mut entries = Vec::with_capacity(rows.len());
+        let mut invalid = Vec::new();
+        for row in rows {
+            let id: i64 = row.get(0);
+            match serde_json::from_value(row.get(2)) {
+                Ok(event) => entries.push(OutboxEntry { id, topic: row.get(1), event }),
+                Err(error) => {
+                    log::error!("Skipping invalid event {} in the outbox: {:?}", id, error);
+                    invalid.push(id);
+                },
+            }
+        }
+        let (mut published, error) = publish_entries(publisher, entries).await;
+        let count = published.len();
+        published.extend(invalid);
+        transaction.execute(
+            "UPDATE sipin_outbox SET published_at = now() WHERE id = ANY($1)", &[&published],
+        ).await?;
+        transaction.commit().await?;
+        match error {
+            Some(error) => Err(error),
+            None => Ok(count),
+        }
+    }
+
+    /// Delete the events that were published longer than `retention` ago.
+    pub async fn clean(&self) -> Result<u64, tokio_postgres::Error> {
+        let cutoff = chrono::Utc::now() - chrono::Duration::from_std(self.retention).unwrap_or_else(|_| chrono::Duration::days(1));
+        self.client.execute("DELETE FROM sipin_outbox WHERE published_at < $1", &[&cutoff]).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A stand-in for Pulsar, failing on the events with the given ids.
+    #[derive(Default)]
+    struct StandIn {
+        published: Vec<(String, CloudEvent)>,
+        fail_on: Vec<String>,
+    }
+
+    impl Publisher for StandIn {
+        async fn publish(&mut self, topic: &str, event: CloudEvent) -> Result<(), anyhow::Error> {
+            if self.fail_on.contains(&event.id) {
+                return Err(anyhow::anyhow!("broker unavailable"));
+            }
+            self.published.push((topic.to_string(), event));
+            Ok(())
+        }
+    }
+
+    fn entry(id: i64) -> OutboxEntry {
+        let event = CloudEvent {
+            id: id.to_string(),
+            ..crate::testing::event("abc", "be.meemoo.sipin.sip.status.changed", "2024-05-20T10:00:00Z", serde_json::json!({}))
+        };
+        OutboxEntry { id, topic: String::from("public/sipin/status"), event }
+    }
+
+    fn relay(client: Client) -> OutboxRelay {
+        OutboxRelay {
+            client,
+            wake: Arc::new(Notify::new()),
+            interval: Duration::from_secs(1),
+            batch_size: 2,
+            retention: Duration::from_secs(86400),
+        }
+    }
+
+    async fn add(client: &Client, payload: serde_json::Value) {
+        client.execute("INSERT INTO sipin_outbox (topic, payload) VALUES ('public/sipin/status', $1)", &[&payload]).await.unwrap();
+    }
+
+    async fn pending(client: &Client) -> Vec<i64> {
+        client.query("SELECT id FROM sipin_outbox WHERE published_at IS NULL ORDER BY id", &[]).await.unwrap()
+            .iter().map(|row| row.get(0)).collect()
+    }
+
+    #[tokio::test]
+    #[ignore = "needs PULSAR2DB_TEST_POSTGRES"]
+    async fn relay_publishes_batches_in_order() {
+        let client = crate::testing::database("relay_publishes_batches_in_order", crate::testing::DDL).await;
+        for id in 1..=3 {
+            add(&client, serde_json::to_value(entry(id).event).unwrap()).await;
+        }
+        let mut relay = relay(client);
+        let mut publisher = StandIn::default();
+        assert_eq!(relay.relay(&mut publisher).await.unwrap(), 2);
+        assert_eq!(pending(&relay.client).await, vec![3]);
+        assert_eq!(relay.relay(&mut publisher).await.unwrap(), 1);
+        assert_eq!(relay.relay(&mut publisher).await.unwrap(), 0);
+        assert!(pending(&relay.client).await.is_empty());
+        let ids: Vec<&str> = publisher.published.iter().map(|(_, event)| event.id.as_str()).collect();
+        assert_eq!(ids, vec!["1", "2", "3"]);
+    }
+    #[tokio::test]
+    #[ignore = "needs PULSAR2DB_TEST_POSTGRES"]
+    async fn relay_marks_published_before_failure() {
+        let client = crate::testing::database("relay_marks_published_before_failure", crate::testing::DDL).await;
+        add(&client, serde_json::json!({"not": "an event"})).await;
+        for id in 2..=3 {
+            add(&client, serde_json::to_value(entry(id).event).unwrap()).await;
+        }
+        let mut relay = relay(client);
+        relay.batch_size = 10;
+        let mut publisher = StandIn { fail_on: vec![String::from("3")], ..StandIn::default() };
+        assert!(relay.relay(&mut publisher).await.is_err());
+        // The invalid event is skipped, the failed one is retried.
+        assert_eq!(pending(&relay.client).await, vec![3]);
+        publisher.fail_on.clear();
+        assert_eq!(relay.relay(&mut publisher).await.unwrap(), 1);
+        assert!(pending(&relay.client).await.is_empty());
+    }
+    #[tokio::test]
+    #[ignore = "needs PULSAR2DB_TEST_POSTGRES"]
+    async fn relay_one_instance_at_a_time() {
+        let name = "relay_one_instance_at_a_time";
+        let mut client = crate::testing::database(name, crate::testing::DDL).await;
+        add(&client, serde_json::to_value(entry(1).event).unwrap()).await;
+        let other = client.transaction().await.unwrap();
+        other.execute("SELECT pg_advisory_xact_lock(hashtext('pulsar2db.outbox'))", &[]).await.unwrap();
+        let mut relay = relay(crate::testing::connection(name).await);
+        let mut publisher = StandIn::default();
+        assert_eq!(relay.relay(&mut publisher).await.unwrap(), 0);
+        other.commit().await.unwrap();
+        assert_eq!(relay.relay(&mut publisher).await.unwrap(), 1);
+    }
+    #[tokio::test]
+    async fn publish_entries_in_order() {
+        let mut publisher = StandIn::default();
+        let (published, error) = publish_entries(&mut publisher, vec![entry(1), entry(2), entry(3)]).await;
+        assert_eq!(published, vec![1, 2, 3]);
+        assert!(error.is_none());
+        let ids: Vec<&str> = publisher.published.iter().map(|(_, event)| event.id.as_str()).collect();
+        assert_eq!(ids, vec!["1", "2", "3"]);
+        assert_eq!(publisher.published[0].0, "public/sipin/status");
+    }
+    #[tokio::test]
+    async fn publish_entries_stops_at_failure() {
+        let mut publisher = StandIn { fail_on: vec![String::from("2")], ..StandIn::default() };
+        let (published, error) = publish_entries(&mut publisher, vec![entry(1), entry(2), entry(3)]).await;
+        assert_eq!(published, vec![1]);
+        assert!(error.is_some());
+        assert_eq!(publisher.published.len(), 1);
+    }
+}
@@ -0,0 +1,277 @@
+use std::time::Duration;
+use chrono::{Datelike, NaiveDate, Utc};
+use tokio_postgres::{Client, Transaction};
+use crate::stalled::TERMINAL_STATUSES;
+
+/// A calendar month, the unit in which `sipin_sips` is partitioned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Month {
+    pub year: i32,
+    pub month: u32,
+}
+
+impl Month {
+    pub fn of(date: NaiveDate) -> Month {
+        Month { year: date.year(), month: date.month() }
+    }
+
+    /// The month `months` months after this one (before, if negative).
+    pub fn plus(self, months: i32) -> Month {
+        let index = self.year * 12 + self.month as i32 - 1 + months;
+        Month { year: index.div_euclid(12), month: index.rem_euclid(12) as u32 + 1 }
+    }
+
+    pub fn first_day(self) -> NaiveDate {
+        NaiveDate::from_ymd_opt(self.year, self.month, 1).unwrap()
+    }
+
+    /// The name of the partition holding the SIPs first seen in this month,
+    /// eg. `sipin_sips_p202405`.
+    pub fn partition_name(self) -> String {
+        format!("sipin_sips_p{:04}{:02}", self.year, self.month)
+    }
+
+    /// The month of a partition, or `None` if the name isn't one of ours
+    /// (eg. the default partition).
+    pub fn from_partition_name(name: &str) -> Option<Month> {
+        let suffix = name.strip_prefix("sipin_sips_p")?;
+        if suffix.len() != 6 || !suffix.chars().all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+        let month = Month { year: suffix[..4].parse().ok()?, month: suffix[4..].parse().ok()? };
+        (1..=12).contains(&month.month).then_some(month)
+    }
+}
+
+/// The condition for a SIP in `sipin_sips` (as `s`) that isn't finished yet:
+/// it didn't reach a terminal status (`$1`), wasn't resolved or ignored by
+/// hand, and its last event didn't fail.
+const UNFINISHED: &str = "s.status <> ALL($1) AND s.resolution IS NULL
+    AND NOT EXISTS (SELECT FROM public.sipin_sip_stages st
+        WHERE st.correlation_id = s.correlation_id AND st.event_type = s.last_event_type
+        AND st.outcome <> 'success')";
+
+/// Whether `name` can be used as the archive schema: a plain identifier.
+pub fn valid_schema_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Maintains the monthly partitions of a `sipin_sips` table that is range
+/// partitioned on `first_event_date` (see `ddl_partitioned.sql`).
+///
+/// Partitions are created `months_ahead` months in advance. When
+/// `retention_months` is set, partitions for months that ended more than that
+/// many months ago are detached, provided all of their SIPs are finished (see
+/// `UNFINISHED`). Detached partitions are moved to `archive_schema`, if set,
+/// so they can be dumped and dropped separately.
+pub struct PartitionManager {
+    pub client: Client,
+    pub interval: Duration,
+    pub months_ahead: u32,
+    pub retention_months: u32,
+    pub archive_schema: String,
+}
+
+impl PartitionManager {
+    pub async fn run(mut self) {
+        let mut interval = tokio::time::interval(self.interval);
+        loop {
+            interval.tick().await;
+            if let Err(error) = self.maintain().await {
+                log::error!("Problem while maintaining the sipin_sips partitions: {:?}", error);
+            }
+        }
+    }
+
+    async fn maintain(&mut self) -> Result<(), tokio_postgres::Error> {
+        let transaction = self.client.transaction().await?;
+        transaction.execute("SELECT pg_advisory_xact_lock(hashtext('pulsar2db.partitions'))", &[]).await?;
+        let partitioned: bool = transaction.query_one(
+            "SELECT relkind = 'p' FROM pg_class WHERE oid = 'public.sipin_sips'::regclass", &[],
+        ).await?.get(0);
+        if !partitioned {
+            log::error!("Partitioning is enabled, but sipin_sips is not a partitioned table");
+            return transaction.commit().await;
+        }
+
+        let current = Month::of(Utc::now().naive_utc().date());
+        for offset in 0..=self.months_ahead as i32 {
+            let month = current.plus(offset);
+            let created = transaction.query_one(
+                "SELECT to_regclass('public.' || $1) IS NULL", &[&month.partition_name()],
+            ).await?.get::<_, bool>(0);
+            if created {
+                create_partition(&transaction, month).await?;
+            }
+        }
+
+        if self.retention_months > 0 {
+            let cutoff = current.plus(-(self.retention_months as i32));
+            let rows = transaction.query(
+                "SELECT c.relname::text FROM pg_inherits i
+                JOIN pg_class c ON c.oid = i.inhrelid
+                WHERE i.inhparent = 'public.sipin_sips'::regclass", &[],
+            ).await?;
+            for row in rows {
+                let name: String = row.get(0);
+                let month = match Month::from_partition_name(&name) {
+                    Some(month) if month.plus(1) <= cutoff => month,
+                    _ => continue,
+                };
+                let incomplete: i64 = transaction.query_one(
+                    format!("SELECT count(*) FROM public.{} s WHERE {}", name, UNFINISHED).as_str(),
+                    &[&TERMINAL_STATUSES.to_vec()],
+                ).await?.get(0);
+                if incomplete > 0 {
+                    log::warn!("Keeping partition {}: {} SIPs from {}-{:02} are not completed", name, incomplete, month.year, month.month);
+                    continue;
+                }
+                log::info!("Detaching partition {}", name);
+                transaction.batch_execute(&format!("ALTER TABLE public.sipin_sips DETACH PARTITION public.{}", name)).await?;
+                if !self.archive_schema.is_empty() {
+                    transaction.batch_execute(&format!(
+                        "CREATE SCHEMA IF NOT EXISTS {0}; ALTER TABLE public.{1} SET SCHEMA {0}",
+                        self.archive_schema, name,
+                    )).await?;
+                }
+            }
+        }
+        transaction.commit().await
+    }
+}
+
+/// Create the partition for `month`. SIPs of that month in the default
+/// partition would violate its bounds, so they are moved to the new
+/// partition. Writes to `sipin_sips` wait until the transaction commits, so
+/// none of them misses a SIP that is being moved.
+async fn create_partition(transaction: &Transaction<'_>, month: Month) -> Result<(), tokio_postgres::Error> {
+    let (name, from, to) = (month.partition_name(), month.first_day(), month.plus(1).first_day());
+    log::info!("Creating partition {}", name);
+    let default: Option<String> = transaction.query_opt(
+        "SELECT c.relname::text FROM pg_partitioned_table p
+        JOIN pg_class c ON c.oid = p.partdefid
+        WHERE p.partrelid = 'public.sipin_sips'::regclass", &[],
+    ).await?.map(|row| row.get(0));
+    let mut moved = 0;
+    if let Some(default) = &default {
+        transaction.batch_execute(&format!(
+            "LOCK TABLE public.sipin_sips IN SHARE ROW EXCLUSIVE MODE;
+            CREATE TEMPORARY TABLE sipin_sips_moved (LIKE public.sipin_sips) ON COMMIT DROP;
+            WITH moved AS (
+                DELETE FROM public.{} WHERE first_event_date >= '{}' AND first_event_date < '{}' RETURNING *)
+            INSERT INTO sipin_sips_moved SELECT * FROM moved",
+            default, from, to,
+        )).await?;
+        moved = transaction.query_one("SELECT count(*) FROM sipin_sips_moved", &[]).await?.get::<_, i64>(0);
+    }
+    transaction.batch_execute(&format!(
+        "CREATE TABLE public.{} PARTITION OF public.sipin_sips FOR VALUES FROM ('{}') TO ('{}')",
+        name, from, to,
+    )).await?;
+    if default.is_some() {
+        if moved > 0 {
+            // Without the generated columns, which can't be inserted
+            let columns: String = transaction.query_one(
+                "SELECT string_agg(quote_ident(attname), ', ' ORDER BY attnum) FROM pg_attribute
+                WHERE attrelid = 'public.sipin_sips'::regclass AND attnum > 0 AND NOT attisdropped AND attgenerated = ''", &[],
+            ).await?.get(0);
+            transaction.batch_execute(&format!(
+                "INSERT INTO public.sipin_sips ({0}) SELECT {0} FROM sipin_sips_moved", columns,
+            )).await?;
+            log::info!("Moved {} SIPs from the default partition to {}", moved, name);
+        }
+        transaction.batch_execute("DROP TABLE sipin_sips_moved").await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn month_add_across_years() {
+        let month = Month { year: 2024, month: 11 };
+        assert_eq!(month.plus(2), Month { year: 2025, month: 1 });
+        assert_eq!(month.plus(-11), Month { year: 2023, month: 12 });
+        assert_eq!(month.plus(0), month);
+    }
+    #[test]
+    fn partition_name_round_trip() {
+        let month = Month { year: 2024, month: 5 };
+        assert_eq!(month.partition_name(), "sipin_sips_p202405");
+        assert_eq!(Month::from_partition_name("sipin_sips_p202405"), Some(month));
+    }
+    #[test]
+    fn from_partition_name_rejects_others() {
+        assert_eq!(Month::from_partition_name("sipin_sips_default"), None);
+        assert_eq!(Month::from_partition_name("sipin_sips_p202413"), None);
+        assert_eq!(Month::from_partition_name("sipin_sip_stages"), None);
+    }
+    fn manager(client: Client, retention_months: u32) -> PartitionManager {
+        PartitionManager {
+            client,
+            interval: Duration::from_secs(86400),
+            months_ahead: 0,
+            retention_months,
+            archive_schema: String::from("archive"),
+        }
+    }
+
+    async fn insert(client: &Client, correlation_id: &str, first_event_date: &str, status: &str, resolution: Option<&str>) {
+        client.execute(
+            "INSERT INTO sipin_sips (correlation_id, first_event_date, last_event_type, last_event_date, status, resolution,
+                md5_hash_essence_manifest, md5_hash_essence_sidecar)
+            VALUES ($1, $2::text::timestamptz, 'be.meemoo.sipin.bag.unzip', $2::text::timestamptz, $3, $4, 'aa', 'bb')",
+            &[&correlation_id, &first_event_date, &status, &resolution],
+        ).await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs PULSAR2DB_TEST_POSTGRES"]
+    async fn create_partition_moves_default_rows() {
+        let client = crate::testing::partitioned_database("create_partition_moves_default_rows").await;
+        let now = Utc::now().to_rfc3339();
+        insert(&client, "abc", &now, "BAG_UNZIPPED", None).await;
+        insert(&client, "old", "2020-01-15T10:00:00Z", "BAG_UNZIPPED", None).await;
+        let mut manager = manager(client, 0);
+        manager.maintain().await.unwrap();
+        let partition = Month::of(Utc::now().naive_utc().date()).partition_name();
+        let row = manager.client.query_one(
+            "SELECT tableoid::regclass::text, checksum_mismatch FROM sipin_sips WHERE correlation_id = 'abc'", &[],
+        ).await.unwrap();
+        assert_eq!(row.get::<_, String>(0), partition);
+        assert_eq!(row.get::<_, Option<bool>>(1), Some(true));
+        let row = manager.client.query_one("SELECT tableoid::regclass::text FROM sipin_sips WHERE correlation_id = 'old'", &[]).await.unwrap();
+        assert_eq!(row.get::<_, String>(0), "sipin_sips_default");
+    }
+    #[tokio::test]
+    #[ignore = "needs PULSAR2DB_TEST_POSTGRES"]
+    async fn retention_detaches_finished_partitions() {
+        let client = crate::testing::partitioned_database("retention_detaches_finished_partitions").await;
+        client.batch_execute(
+            "CREATE TABLE public.sipin_sips_p202001 PARTITION OF public.sipin_sips FOR VALUES FROM ('2020-01-01') TO ('2020-02-01');
+            CREATE TABLE public.sipin_sips_p202002 PARTITION OF public.sipin_sips FOR VALUES FROM ('2020-02-01') TO ('2020-03-01');
+            INSERT INTO sipin_sip_stages (correlation_id, stage, event_type, event_date, outcome)
+            VALUES ('failed', 'unzip', 'be.meemoo.sipin.bag.unzip', '2020-01-15', 'fail')",
+        ).await.unwrap();
+        insert(&client, "delivered", "2020-01-15T10:00:00Z", "AIP_DELIVERED_TO_MAM", None).await;
+        insert(&client, "ignored", "2020-01-15T10:00:00Z", "BAG_UNZIPPED", Some("ignored")).await;
+        insert(&client, "failed", "2020-01-15T10:00:00Z", "BAG_UNZIPPED", None).await;
+        insert(&client, "unzipped", "2020-02-15T10:00:00Z", "BAG_UNZIPPED", None).await;
+        let mut manager = manager(client, 1);
+        manager.maintain().await.unwrap();
+        let partitions: Vec<String> = manager.client.query(
+            "SELECT c.oid::regclass::text FROM pg_class c WHERE c.relname IN ('sipin_sips_p202001', 'sipin_sips_p202002') ORDER BY c.relname", &[],
+        ).await.unwrap().iter().map(|row| row.get(0)).collect();
+        assert_eq!(partitions, ["archive.sipin_sips_p202001", "sipin_sips_p202002"]);
+    }
+    #[test]
+    fn valid_schema_names() {
+        assert!(valid_schema_name("archive"));
+        assert!(valid_schema_name("_sipin_archive2"));
+        assert!(!valid_schema_name("archive; DROP TABLE sipin_sips"));
+        assert!(!valid_schema_name(""));
+    }
+}
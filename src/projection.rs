@@ -0,0 +1,203 @@
+use std::fs;
+use regex::Regex;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use tokio_postgres::Client;
+use crate::CloudEvent;
+
+/// A projection: builds final state in its own table from the events on its
+/// own topics, with one row per key.
+///
+/// Projections are configured in a JSON file, eg.:
+///
+/// ```json
+/// [{
+///     "name": "ingest_requests",
+///     "topics": ["public/default/be.meemoo.ingest.request"],
+///     "table": "ingest_requests",
+///     "key": {"column": "request_id", "field": "/data/request_id"},
+///     "columns": [
+///         {"column": "first_event_date", "field": "time", "keep": "first"},
+///         {"column": "last_event_type", "field": "type"},
+///         {"column": "status", "field": "/data/status"}
+///     ]
+/// }]
+/// ```
+#[derive(Deserialize, Debug, Clone)]
+pub struct Projection {
+    pub name: String,
+    pub topics: Vec<String>,
+    /// Defaults to the main subscription name suffixed with the name of the
+    /// projection.
+    #[serde(default)]
+    pub subscription_name: Option<String>,
+    pub table: String,
+    pub key: ColumnMapping,
+    pub columns: Vec<ColumnMapping>,
+}
+
+/// Maps a field of the CloudEvent onto a column.
+///
+/// The field is either the name of a CloudEvent attribute (eg. `subject` or
+/// `correlation_id`) or, when it starts with a `/`, a JSON pointer into the
+/// event (eg. `/data/s3_message/Records/0/s3/bucket/name`).
+#[derive(Deserialize, Debug, Clone)]
+pub struct ColumnMapping {
+    pub column: String,
+    pub field: String,
+    #[serde(default)]
+    pub keep: Keep,
+}
+
+/// Which value to keep when a row already has a value for a column.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Keep {
+    /// The value of the latest event that has one.
+    #[default]
+    Latest,
+    /// The value of the first event that had one.
+    First,
+}
+
+/// Load the projections from a JSON file and validate them.
+pub fn load_projections(path: &str) -> Result<Vec<Projection>, anyhow::Error> {
+    let projections: Vec<Projection> = serde_json::from_str(&fs::read_to_string(path)?)?;
+    for projection in &projections {
+        projection.validate()?;
+    }
+    Ok(projections)
+}
+
+impl Projection {
+    /// Check that the table and columns are plain (optionally schema
+    /// qualified) identifiers, since they end up in the upsert statement.
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        let identifier = Regex::new(r"^[A-Za-z_][A-Za-z0-9_]*(\.[A-Za-z_][A-Za-z0-9_]*)?$").unwrap();
+        let names = std::iter::once(&self.table)
+            .chain(std::iter::once(&self.key.column))
+            .chain(self.columns.iter().map(|mapping| &mapping.column));
+        for name in names {
+            if !identifier.is_match(name) {
+                return Err(anyhow::anyhow!("projection {}: invalid identifier {:?}", self.name, name));
+            }
+        }
+        if self.topics.is_empty() {
+            return Err(anyhow::anyhow!("projection {}: no topics", self.name));
+        }
+        Ok(())
+    }
+
+    /// The values of the key and all columns for an event, as a JSON object
+    /// keyed on column name. Returns `None` if the event has no key.
+    pub fn values(&self, event: &CloudEvent) -> Result<Option<Value>, serde_json::Error> {
+        let event = serde_json::to_value(event)?;
+        let key = match field(&event, &self.key.field) {
+            Some(key) if !key.is_null() => key.clone(),
+            _ => return Ok(None),
+        };
+        let mut values = Map::new();
+        values.insert(self.key.column.clone(), key);
+        for mapping in &self.columns {
+            let value = field(&event, &mapping.field).cloned().unwrap_or(Value::Null);
+            values.insert(mapping.column.clone(), value);
+        }
+        Ok(Some(Value::Object(values)))
+    }
+
+    /// The upsert statement for this projection. Its single parameter is the
+    /// JSON object from `values`, which Postgres converts to the column types
+    /// of the table. The key column needs a unique constraint.
+    pub fn statement(&self) -> String {
+        let columns: Vec<&str> = std::iter::once(self.key.column.as_str())
+            .chain(self.columns.iter().map(|mapping| mapping.column.as_str()))
+            .collect();
+        let updates: Vec<String> = self.columns.iter()
+            .map(|mapping| match mapping.keep {
+                Keep::Latest => format!("{0} = COALESCE(EXCLUDED.{0}, existing.{0})", mapping.column),
+                Keep::First => format!("{0} = COALESCE(existing.{0}, EXCLUDED.{0})", mapping.column),
+            })
+            .collect();
+        let conflict = match updates.is_empty() {
+            true => String::from("DO NOTHING"),
+            false => format!("DO UPDATE SET {}", updates.join(", ")),
+        };
+        format!(
+            "INSERT INTO {0} AS existing ({1})
+            SELECT {1} FROM jsonb_populate_record(NULL::{0}, $1)
+            ON CONFLICT ({2}) {3}",
+            self.table, columns.join(", "), self.key.column, conflict,
+        )
+    }
+
+    /// Upsert the state for a single event. Returns false if the event has no
+    /// key and was skipped.
+    pub async fn upsert(&self, client: &Client, event: &CloudEvent) -> Result<bool, anyhow::Error> {
+        let values = match self.values(event)? {
+            Some(values) => values,
+            None => {
+                log::warn!("Projection {}: no key {} in event {}", &self.name, &self.key.field, &event.id);
+                return Ok(false);
+            },
+        };
+        client.execute(self.statement().as_str(), &[&values]).await?;
+        Ok(true)
+    }
+}
+
+/// Look up a CloudEvent attribute or a JSON pointer in a serialized event.
+fn field<'a>(event: &'a Value, field: &str) -> Option<&'a Value> {
+    match field.starts_with('/') {
+        true => event.pointer(field),
+        false => event.get(field),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::event;
+
+    fn projection() -> Projection {
+        serde_json::from_str(r#"{
+            "name": "requests",
+            "topics": ["public/default/requests"],
+            "table": "public.requests",
+            "key": {"column": "request_id", "field": "/data/request_id"},
+            "columns": [
+                {"column": "first_event_date", "field": "time", "keep": "first"},
+                {"column": "status", "field": "/data/status"}
+            ]
+        }"#).unwrap()
+    }
+
+    #[test]
+    fn values_from_attributes_and_pointers() {
+        let values = projection().values(&event("abc", "be.meemoo.request", "2022-05-20T10:00:00Z", serde_json::json!({"request_id": "r1", "status": "OK"}))).unwrap();
+        assert_eq!(values, Some(serde_json::json!({
+            "request_id": "r1",
+            "first_event_date": "2022-05-20T10:00:00Z",
+            "status": "OK",
+        })));
+    }
+    #[test]
+    fn values_without_key() {
+        let values = projection().values(&event("abc", "be.meemoo.request", "2022-05-20T10:00:00Z", serde_json::json!({"status": "OK"}))).unwrap();
+        assert_eq!(values, None);
+    }
+    #[test]
+    fn statement_keeps_first_and_latest() {
+        let statement = projection().statement();
+        assert!(statement.contains("INSERT INTO public.requests AS existing (request_id, first_event_date, status)"));
+        assert!(statement.contains("first_event_date = COALESCE(existing.first_event_date, EXCLUDED.first_event_date)"));
+        assert!(statement.contains("status = COALESCE(EXCLUDED.status, existing.status)"));
+        assert!(statement.contains("ON CONFLICT (request_id)"));
+    }
+    #[test]
+    fn validate_rejects_invalid_identifiers() {
+        let mut projection = projection();
+        assert!(projection.validate().is_ok());
+        projection.table = String::from("requests; DROP TABLE sipin_sips");
+        assert!(projection.validate().is_err());
+    }
+}
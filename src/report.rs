@@ -0,0 +1,288 @@
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use chrono::{DateTime, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use rust_xlsxwriter::{Format, Workbook};
+use tokio_postgres::{Client, Row};
+use crate::api::SipQuery;
+
+/// The columns of a report, in order.
+pub const COLUMNS: [&str; 12] = [
+    "correlation_id", "cp_id", "local_id", "pid", "bag_name", "essence_filename",
+    "bag_filesize", "status", "stalled", "resolution", "first_event_date", "last_event_date",
+];
+
+/// The label of a status, for people rather than machines. Unknown statuses
+/// are returned as they are.
+pub fn status_label(status: &str) -> &str {
+    match status {
+        "S3_OBJECT_CREATED" => "Uploaded to S3",
+        "SIP_CREATED" => "Delivered on FTP",
+        "BAG_TRANSFERRED_TO_SIPIN" => "Transferred to SIP ingest",
+        "BAG_UNZIPPED" => "Unzipped",
+        "BAG_VALIDATED" => "Bag validated",
+        "SIP_VALIDATED" => "SIP validated",
+        "AIP_CREATED" | "MH-SIP_CREATED" => "Prepared for MediaHaven",
+        "AIP_DELIVERED_TO_MAM" => "Archived in MediaHaven",
+        _ => status,
+    }
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReportFormat {
+    Csv,
+    Xlsx,
+}
+
+/// The arguments of the `report` subcommand.
+#[derive(clap::Args, Debug)]
+pub struct ReportArgs {
+    /// Only the SIPs of this CP
+    #[arg(long)]
+    pub cp_id: Option<String>,
+    /// Only the SIPs with this status, eg. BAG_UNZIPPED
+    #[arg(long)]
+    pub status: Option<String>,
+    /// First day of delivery to include (YYYY-MM-DD, in the timezone)
+    #[arg(long)]
+    pub from: Option<NaiveDate>,
+    /// First day of delivery to leave out (YYYY-MM-DD, in the timezone)
+    #[arg(long)]
+    pub to: Option<NaiveDate>,
+    #[arg(long, value_enum, default_value = "csv")]
+    pub format: ReportFormat,
+    /// Timezone of the dates, in the arguments and the report
+    #[arg(long, default_value = "Europe/Brussels")]
+    pub timezone: Tz,
+    /// File to write the report to. CSV reports go to stdout without one.
+    #[arg(long, short)]
+    pub output: Option<PathBuf>,
+}
+
+/// Midnight at the start of a day in a timezone, or an error for the days
+/// on which the clocks skip midnight.
+pub fn start_of_day(date: NaiveDate, timezone: &Tz) -> Result<DateTime<Utc>, anyhow::Error> {
+    let midnight = date.and_hms_opt(0, 0, 0).expect("midnight is a valid time");
+    match timezone.from_local_datetime(&midnight).earliest() {
+        Some(start) => Ok(start.with_timezone(&Utc)),
+        None => Err(anyhow::anyhow!("{} has no midnight in {}", date, timezone.name())),
+    }
+}
+
+impl ReportArgs {
+    /// The filters of the report, as for the API.
+    pub fn query(&self) -> Result<SipQuery, anyhow::Error> {
+        Ok(SipQuery {
+            cp_id: self.cp_id.clone(),
+            status: self.status.clone(),
+            from: self.from.map(|date| start_of_day(date, &self.timezone)).transpose()?,
+            to: self.to.map(|date| start_of_day(date, &self.timezone)).transpose()?,
+            ..SipQuery::default()
+        })
+    }
+}
+
+/// A value in a report.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Cell {
+    Text(String),
+    Number(f64),
+    /// A date and time, in the timezone of the report.
+    Date(NaiveDateTime),
+    Empty,
+}
+
+impl Cell {
+    fn text(&self) -> String {
+        match self {
+            Cell::Text(text) => text.clone(),
+            Cell::Number(number) => number.to_string(),
+            Cell::Date(date) => date.format("%Y-%m-%d %H:%M:%S").to_string(),
+            Cell::Empty => String::new(),
+        }
+    }
+}
+
+/// A SIP in a report.
+#[derive(Debug, Clone)]
+pub struct ReportRow {
+    pub correlation_id: String,
+    pub cp_id: Option<String>,
+    pub local_id: Option<String>,
+    pub pid: Option<String>,
+    pub bag_name: Option<String>,
+    pub essence_filename: Option<String>,
+    pub bag_filesize: Option<i64>,
+    pub status: String,
+    pub stalled: bool,
+    pub resolution: Option<String>,
+    pub first_event_date: DateTime<Utc>,
+    pub last_event_date: DateTime<Utc>,
+}
+
+impl ReportRow {
+    /// The row for the `COLUMNS` selected from `sipin_sips`.
+    pub fn from_row(row: &Row) -> ReportRow {
+        ReportRow {
+            correlation_id: row.get("correlation_id"),
+            cp_id: row.get("cp_id"),
+            local_id: row.get("local_id"),
+            pid: row.get("pid"),
+            bag_name: row.get("bag_name"),
+            essence_filename: row.get("essence_filename"),
+            bag_filesize: row.get("bag_filesize"),
+            status: row.get("status"),
+            stalled: row.get("stalled"),
+            resolution: row.get("resolution"),
+            first_event_date: row.get("first_event_date"),
+            last_event_date: row.get("last_event_date"),
+        }
+    }
+
+    /// The cells for the `COLUMNS`, with the dates in `timezone`.
+    pub fn cells(&self, timezone: &Tz) -> [Cell; 12] {
+        let text = |value: &Option<String>| value.clone().map_or(Cell::Empty, Cell::Text);
+        let date = |value: &DateTime<Utc>| Cell::Date(value.with_timezone(timezone).naive_local());
+        [
+            Cell::Text(self.correlation_id.clone()),
+            text(&self.cp_id),
+            text(&self.local_id),
+            text(&self.pid),
+            text(&self.bag_name),
+            text(&self.essence_filename),
+            self.bag_filesize.map_or(Cell::Empty, |size| Cell::Number(size as f64)),
+            Cell::Text(status_label(&self.status).to_string()),
+            Cell::Text(String::from(if self.stalled { "yes" } else { "no" })),
+            text(&self.resolution),
+            date(&self.first_event_date),
+            date(&self.last_event_date),
+        ]
+    }
+}
+
+/// The SIPs matching the filters of the report, oldest first.
+pub async fn fetch(client: &Client, query: &SipQuery) -> Result<Vec<ReportRow>, tokio_postgres::Error> {
+    let (filter, params) = query.filter();
+    let statement = format!(
+        "SELECT {} FROM sipin_sips {} ORDER BY first_event_date, correlation_id",
+        COLUMNS.join(", "), filter,
+    );
+    let rows = client.query(statement.as_str(), &params).await?;
+    Ok(rows.iter().map(ReportRow::from_row).collect())
+}
+
+pub fn write_csv<W: Write>(rows: &[ReportRow], timezone: &Tz, writer: W) -> Result<(), anyhow::Error> {
+    let mut writer = csv::Writer::from_writer(writer);
+    writer.write_record(COLUMNS)?;
+    for row in rows {
+        writer.write_record(row.cells(timezone).iter().map(Cell::text))?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// The report as an XLSX workbook, with a bold, frozen header row and an
+/// autofilter. Dates are written as dates, so they sort and filter as such.
+pub fn write_xlsx(rows: &[ReportRow], timezone: &Tz) -> Result<Vec<u8>, anyhow::Error> {
+    let mut workbook = Workbook::new();
+    let worksheet = workbook.add_worksheet();
+    worksheet.set_name("SIPs")?;
+    let bold = Format::new().set_bold();
+    let date = Format::new().set_num_format("yyyy-mm-dd hh:mm:ss");
+    for (col, column) in COLUMNS.iter().enumerate() {
+        worksheet.write_string_with_format(0, col as u16, *column, &bold)?;
+    }
+    for (i, row) in rows.iter().enumerate() {
+        for (col, cell) in row.cells(timezone).iter().enumerate() {
+            match cell {
+                Cell::Text(text) => worksheet.write_string(i as u32 + 1, col as u16, text)?,
+                Cell::Number(number) => worksheet.write_number(i as u32 + 1, col as u16, *number)?,
+                Cell::Date(value) => worksheet.write_datetime_with_format(i as u32 + 1, col as u16, value, &date)?,
+                Cell::Empty => worksheet,
+            };
+        }
+    }
+    worksheet.set_freeze_panes(1, 0)?;
+    worksheet.autofilter(0, 0, rows.len() as u32, COLUMNS.len() as u16 - 1)?;
+    worksheet.autofit();
+    Ok(workbook.save_to_buffer()?)
+}
+
+/// Run the `report` subcommand.
+pub async fn run(client: &Client, args: &ReportArgs) -> Result<(), anyhow::Error> {
+    let rows = fetch(client, &args.query()?).await?;
+    log::info!("Writing a report of {} SIPs", rows.len());
+    match (args.format, &args.output) {
+        (ReportFormat::Csv, Some(path)) => write_csv(&rows, &args.timezone, fs::File::create(path)?),
+        (ReportFormat::Csv, None) => write_csv(&rows, &args.timezone, std::io::stdout().lock()),
+        (ReportFormat::Xlsx, Some(path)) => Ok(fs::write(path, write_xlsx(&rows, &args.timezone)?)?),
+        (ReportFormat::Xlsx, None) => Err(anyhow::anyhow!("XLSX reports need an --output file")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row() -> ReportRow {
+        ReportRow {
+            correlation_id: String::from("abc"),
+            cp_id: Some(String::from("OR-rf5kf25")),
+            local_id: None,
+            pid: Some(String::from("a1b2c3d4e5")),
+            bag_name: Some(String::from("abc.bag.zip")),
+            essence_filename: None,
+            bag_filesize: Some(1024),
+            status: String::from("AIP_DELIVERED_TO_MAM"),
+            stalled: false,
+            resolution: None,
+            first_event_date: "2024-05-20T09:00:00Z".parse().unwrap(),
+            last_event_date: "2024-12-20T10:30:00Z".parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn status_labels() {
+        assert_eq!(status_label("BAG_UNZIPPED"), "Unzipped");
+        assert_eq!(status_label("MH-SIP_CREATED"), "Prepared for MediaHaven");
+        assert_eq!(status_label("SOMETHING_NEW"), "SOMETHING_NEW");
+    }
+    #[test]
+    fn start_of_day_in_timezone() {
+        let date = NaiveDate::from_ymd_opt(2024, 5, 1).unwrap();
+        assert_eq!(start_of_day(date, &chrono_tz::Europe::Brussels).unwrap(), "2024-04-30T22:00:00Z".parse::<DateTime<Utc>>().unwrap());
+        assert_eq!(start_of_day(date, &chrono_tz::UTC).unwrap(), "2024-05-01T00:00:00Z".parse::<DateTime<Utc>>().unwrap());
+    }
+    #[test]
+    fn start_of_day_without_midnight() {
+        // Daylight saving time started at midnight
+        let date = NaiveDate::from_ymd_opt(2018, 11, 4).unwrap();
+        assert!(start_of_day(date, &chrono_tz::America::Sao_Paulo).is_err());
+        let args = ReportArgs {
+            cp_id: None,
+            status: None,
+            from: Some(date),
+            to: None,
+            format: ReportFormat::Csv,
+            timezone: chrono_tz::America::Sao_Paulo,
+            output: None,
+        };
+        assert!(args.query().is_err());
+    }
+    #[test]
+    fn csv_with_labels_and_local_dates() {
+        let mut output = Vec::new();
+        write_csv(&[row()], &chrono_tz::Europe::Brussels, &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines[0], COLUMNS.join(","));
+        // Summer and winter time
+        assert_eq!(lines[1], "abc,OR-rf5kf25,,a1b2c3d4e5,abc.bag.zip,,1024,Archived in MediaHaven,no,,2024-05-20 11:00:00,2024-12-20 11:30:00");
+    }
+    #[test]
+    fn xlsx_is_a_workbook() {
+        let output = write_xlsx(&[row()], &chrono_tz::Europe::Brussels).unwrap();
+        assert!(output.starts_with(b"PK"));
+    }
+}
@@ -0,0 +1,167 @@
+use std::fs;
+use regex::Regex;
+use serde::Deserialize;
+use tokio_postgres::Client;
+use crate::Config;
+
+/// Decides which objects from S3 notifications become SIPs, based on
+/// include and exclude regexes on the bucket name and the object key.
+///
+/// An object is accepted when it matches the include regex (if any) and
+/// doesn't match the exclude regex (if any), for both the bucket and the key.
+#[derive(Debug, Default)]
+pub struct S3Filter {
+    pub bucket_include: Option<Regex>,
+    pub bucket_exclude: Option<Regex>,
+    pub key_include: Option<Regex>,
+    pub key_exclude: Option<Regex>,
+}
+
+impl S3Filter {
+    pub fn from_config(config: &Config) -> Result<S3Filter, regex::Error> {
+        let compile = |pattern: &str| match pattern.is_empty() {
+            true => Ok(None),
+            false => Regex::new(pattern).map(Some),
+        };
+        Ok(S3Filter {
+            bucket_include: compile(&config.s3_bucket_include)?,
+            bucket_exclude: compile(&config.s3_bucket_exclude)?,
+            key_include: compile(&config.s3_key_include)?,
+            key_exclude: compile(&config.s3_key_exclude)?,
+        })
+    }
+
+    /// Returns true if the object should become a SIP. A missing bucket or
+    /// key only matches when there is no include regex for it.
+    pub fn accepts(&self, bucket: Option<&str>, key: Option<&str>) -> bool {
+        matches(&self.bucket_include, &self.bucket_exclude, bucket)
+            && matches(&self.key_include, &self.key_exclude, key)
+    }
+}
+
+fn matches(include: &Option<Regex>, exclude: &Option<Regex>, value: Option<&str>) -> bool {
+    let included = match (include, value) {
+        (Some(include), Some(value)) => include.is_match(value),
+        (Some(_), None) => false,
+        (None, _) => true,
+    };
+    let excluded = match (exclude, value) {
+        (Some(exclude), Some(value)) => exclude.is_match(value),
+        _ => false,
+    };
+    included && !excluded
+}
+
+/// Maps a bucket, optionally narrowed down to keys with a given prefix, to
+/// the CP that delivers its SIPs there.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct CpMappingEntry {
+    pub bucket: String,
+    #[serde(default)]
+    pub key_prefix: String,
+    pub cp_id: String,
+}
+
+/// The mapping of S3 buckets and key prefixes to `cp_id`s.
+#[derive(Debug, Default)]
+pub struct CpMapping {
+    pub entries: Vec<CpMappingEntry>,
+}
+
+impl CpMapping {
+    /// The `cp_id` for an object: the one of the entry for its bucket with
+    /// the longest key prefix that matches its key.
+    pub fn cp_id(&self, bucket: Option<&str>, key: Option<&str>) -> Option<&str> {
+        let (bucket, key) = (bucket?, key.unwrap_or_default());
+        self.entries.iter()
+            .filter(|entry| entry.bucket == bucket && key.starts_with(&entry.key_prefix))
+            .max_by_key(|entry| entry.key_prefix.len())
+            .map(|entry| entry.cp_id.as_str())
+    }
+}
+
+/// Where the `CpMapping` is loaded from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CpMappingSource {
+    None,
+    /// A JSON file, eg.:
+    /// `[{"bucket": "ingest", "key_prefix": "OR-rf5kf25/", "cp_id": "OR-rf5kf25"}]`
+    File(String),
+    /// The `sipin_cp_mappings` table, see `ddl.sql`.
+    Table,
+}
+
+impl CpMappingSource {
+    pub fn from_config(config: &Config) -> Result<CpMappingSource, anyhow::Error> {
+        match (config.cp_mapping_file.is_empty(), config.cp_mapping_table) {
+            (true, false) => Ok(CpMappingSource::None),
+            (false, false) => Ok(CpMappingSource::File(config.cp_mapping_file.clone())),
+            (true, true) => Ok(CpMappingSource::Table),
+            (false, true) => Err(anyhow::anyhow!("CP_MAPPING_FILE and CP_MAPPING_TABLE can't both be set")),
+        }
+    }
+
+    pub async fn load(&self, client: &Client) -> Result<CpMapping, anyhow::Error> {
+        let entries = match self {
+            CpMappingSource::None => Vec::new(),
+            CpMappingSource::File(path) => serde_json::from_str(&fs::read_to_string(path)?)?,
+            CpMappingSource::Table => client
+                .query("SELECT bucket, key_prefix, cp_id FROM sipin_cp_mappings", &[])
+                .await?
+                .iter()
+                .map(|row| CpMappingEntry {
+                    bucket: row.get(0),
+                    key_prefix: row.get(1),
+                    cp_id: row.get(2),
+                })
+                .collect(),
+        };
+        Ok(CpMapping { entries })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn accepts_everything_without_regexes() {
+        let filter = S3Filter::default();
+        assert!(filter.accepts(Some("ingest"), Some("OR-1/sidecar.xml")));
+        assert!(filter.accepts(None, None));
+    }
+    #[test]
+    fn include_and_exclude_keys() {
+        let filter = S3Filter {
+            key_include: Some(Regex::new(r"\.bag\.zip$").unwrap()),
+            key_exclude: Some(Regex::new(r"(^|/)tmp/").unwrap()),
+            ..S3Filter::default()
+        };
+        assert!(filter.accepts(Some("ingest"), Some("OR-1/sip.bag.zip")));
+        assert!(!filter.accepts(Some("ingest"), Some("OR-1/sidecar.xml")));
+        assert!(!filter.accepts(Some("ingest"), Some("tmp/sip.bag.zip")));
+        assert!(!filter.accepts(Some("ingest"), None));
+    }
+    #[test]
+    fn exclude_buckets() {
+        let filter = S3Filter {
+            bucket_exclude: Some(Regex::new(r"^test-").unwrap()),
+            ..S3Filter::default()
+        };
+        assert!(filter.accepts(Some("ingest"), Some("sip.bag.zip")));
+        assert!(!filter.accepts(Some("test-ingest"), Some("sip.bag.zip")));
+    }
+    #[test]
+    fn cp_id_longest_prefix() {
+        let mapping = CpMapping {
+            entries: serde_json::from_str(r#"[
+                {"bucket": "ingest", "cp_id": "OR-default"},
+                {"bucket": "ingest", "key_prefix": "OR-rf5kf25/", "cp_id": "OR-rf5kf25"},
+                {"bucket": "other", "key_prefix": "OR-rf5kf25/", "cp_id": "OR-other"}
+            ]"#).unwrap(),
+        };
+        assert_eq!(mapping.cp_id(Some("ingest"), Some("OR-rf5kf25/sip.bag.zip")), Some("OR-rf5kf25"));
+        assert_eq!(mapping.cp_id(Some("ingest"), Some("sip.bag.zip")), Some("OR-default"));
+        assert_eq!(mapping.cp_id(Some("unknown"), Some("OR-rf5kf25/sip.bag.zip")), None);
+        assert_eq!(mapping.cp_id(None, Some("OR-rf5kf25/sip.bag.zip")), None);
+    }
+}
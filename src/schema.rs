@@ -0,0 +1,78 @@
+use tokio_postgres::Client;
+
+/// The reporting views, see `views.sql`.
+const VIEWS: &str = include_str!("../views.sql");
+
+/// Create the reporting views, or replace them with their current
+/// definition.
+///
+/// Runs in a single transaction, holding an advisory lock so several
+/// instances starting at the same time don't trip over each other.
+pub async fn create_views(client: &mut Client) -> Result<(), tokio_postgres::Error> {
+    let transaction = client.transaction().await?;
+    transaction.execute("SELECT pg_advisory_xact_lock(hashtext('pulsar2db.views'))", &[]).await?;
+    transaction.batch_execute(VIEWS).await?;
+    transaction.commit().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `sipin_sips` as created by the first version of `ddl.sql`.
+    const FIRST_DDL: &str = "CREATE TABLE public.sipin_sips (
+        row_id serial4 NOT NULL,
+        correlation_id text NOT NULL,
+        bag_name text NULL,
+        cp_id text NULL,
+        local_id varchar(255) NULL,
+        md5_hash_sip text NULL,
+        md5_hash_essence_manifest varchar(32) NULL,
+        md5_hash_essence_sidecar text NULL,
+        essence_filename text NULL,
+        essence_filesize int8 NULL,
+        ingest_host text NULL,
+        ingest_bucket text NULL,
+        ingest_path_or_key text NULL,
+        bag_filesize int8 NULL,
+        pid bpchar(10) NULL,
+        mh_record_id bpchar(64) NULL,
+        first_event_date timestamptz NOT NULL,
+        last_event_type text NOT NULL,
+        last_event_date timestamptz NOT NULL,
+        status text NOT NULL,
+        CONSTRAINT sipin_sips_correlation_id_key UNIQUE (correlation_id),
+        CONSTRAINT sipin_sips_mh_record_id_key UNIQUE (mh_record_id),
+        CONSTRAINT sipin_sips_pid_key UNIQUE (pid),
+        CONSTRAINT sipin_sips_pkey PRIMARY KEY (row_id)
+    );
+    CREATE INDEX sipin_sips_essence_filename_idx ON public.sipin_sips USING btree (essence_filename);
+    CREATE INDEX sipin_sips_md5_hash_sip_idx ON public.sipin_sips USING btree (md5_hash_sip);
+    CREATE INDEX sipin_sips_md5_hash_essence_manifest_idx ON public.sipin_sips USING btree (md5_hash_essence_manifest);";
+    const UPGRADE: &str = include_str!("../upgrade.sql");
+
+    /// The columns, their types and the indexes of the tables, except for
+    /// the type of `row_id`, which `upgrade.sql` leaves alone.
+    async fn schema(client: &Client) -> Vec<String> {
+        client.query(
+            "SELECT concat_ws(' ', table_name, column_name, data_type, is_nullable, is_generated) FROM information_schema.columns
+            WHERE table_schema = 'public' AND column_name <> 'row_id'
+            UNION ALL
+            SELECT indexname FROM pg_indexes WHERE schemaname = 'public'
+            ORDER BY 1", &[],
+        ).await.unwrap().iter().map(|row| row.get(0)).collect()
+    }
+
+    #[tokio::test]
+    #[ignore = "needs PULSAR2DB_TEST_POSTGRES"]
+    async fn upgrade_matches_ddl() {
+        let mut client = crate::testing::database("upgrade_matches_ddl", FIRST_DDL).await;
+        client.batch_execute(UPGRADE).await.unwrap();
+        // And again, on an upgraded database.
+        client.batch_execute(UPGRADE).await.unwrap();
+        create_views(&mut client).await.unwrap();
+        let mut current = crate::testing::database("upgrade_matches_ddl_current", crate::testing::DDL).await;
+        create_views(&mut current).await.unwrap();
+        assert_eq!(schema(&client).await, schema(&current).await);
+    }
+}
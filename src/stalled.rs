@@ -0,0 +1,128 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use chrono::Utc;
+use crate::metrics::Metrics;
+use crate::SharedClient;
+
+/// Statuses after which no further events are expected for a SIP. SIPs in
+/// one of these statuses are never flagged as stalled.
+pub const TERMINAL_STATUSES: [&str; 1] = ["AIP_DELIVERED_TO_MAM"];
+
+/// Parses a list of per-status timeouts in the form
+/// `STATUS=seconds,STATUS=seconds`, eg.:
+/// `BAG_UNZIPPED=3600,S3_OBJECT_CREATED=600`.
+///
+/// Returns an error if an entry can't be parsed.
+pub fn parse_stalled_timeouts(input: &str) -> Result<HashMap<String, u64>, anyhow::Error> {
+    let mut timeouts = HashMap::new();
+    for entry in input.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let (status, seconds) = entry
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("invalid stalled timeout: {}", entry))?;
+        let seconds: u64 = seconds.trim().parse()
+            .map_err(|_| anyhow::anyhow!("invalid stalled timeout: {}", entry))?;
+        timeouts.insert(status.trim().to_string(), seconds);
+    }
+    Ok(timeouts)
+}
+
+/// Periodically scans `sipin_sips` for SIPs in a non-terminal status whose
+/// last event is older than the timeout for that status, and flags them as
+/// stalled.
+///
+/// Statuses without an explicit timeout use `default_timeout`. The flag is
+/// cleared again by the event handlers as soon as a new event comes in. SIPs
+/// that were manually resolved or ignored are skipped.
+pub struct StalledDetector {
+    pub client: Arc<SharedClient>,
+    pub metrics: Arc<Metrics>,
+    pub interval: Duration,
+    pub default_timeout: u64,
+    pub timeouts: HashMap<String, u64>,
+}
+
+impl StalledDetector {
+    pub async fn run(self) {
+        let mut interval = tokio::time::interval(self.interval);
+        loop {
+            interval.tick().await;
+            if let Err(error) = self.scan().await {
+                log::error!("Problem while scanning for stalled SIPs: {:?}", error);
+            }
+        }
+    }
+
+    /// Flag the SIPs that stalled since the last scan, and refresh the gauge.
+    pub async fn scan(&self) -> Result<(), tokio_postgres::Error> {
+        let client = self.client.get().await?;
+        // Statuses with their own timeout are excluded from the default scan.
+        let mut excluded: Vec<&str> = TERMINAL_STATUSES.to_vec();
+        for (status, timeout) in &self.timeouts {
+            excluded.push(status.as_str());
+            let cutoff = Utc::now() - chrono::Duration::seconds(*timeout as i64);
+            let rows = client.query(
+                "UPDATE sipin_sips SET stalled=true
+                WHERE NOT stalled AND resolution IS NULL AND status=$1 AND last_event_date < $2
+                RETURNING correlation_id, status", &[
+                    &status.as_str(),
+                    &cutoff,
+                ],
+            ).await?;
+            self.report(&rows);
+        }
+        let cutoff = Utc::now() - chrono::Duration::seconds(self.default_timeout as i64);
+        let rows = client.query(
+            "UPDATE sipin_sips SET stalled=true
+            WHERE NOT stalled AND resolution IS NULL AND status <> ALL($1) AND last_event_date < $2
+            RETURNING correlation_id, status", &[
+                &excluded,
+                &cutoff,
+            ],
+        ).await?;
+        self.report(&rows);
+
+        // Refresh the gauge from the table so SIPs that got unstuck are
+        // accounted for as well.
+        self.metrics.stalled_sips.reset();
+        let rows = client.query(
+            "SELECT status, count(*) FROM sipin_sips WHERE stalled GROUP BY status", &[],
+        ).await?;
+        for row in rows {
+            let status: &str = row.get(0);
+            let count: i64 = row.get(1);
+            self.metrics.stalled_sips.with_label_values(&[status]).set(count);
+        }
+        Ok(())
+    }
+
+    fn report(&self, rows: &[tokio_postgres::Row]) {
+        for row in rows {
+            let correlation_id: &str = row.get(0);
+            let status: &str = row.get(1);
+            log::warn!("SIP stalled in status {}: correlation_id {}", status, correlation_id);
+            self.metrics.stalled_sips_detected.with_label_values(&[status]).inc();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn parse_stalled_timeouts_empty() {
+        let result = parse_stalled_timeouts("").unwrap();
+        assert!(result.is_empty());
+    }
+    #[test]
+    fn parse_stalled_timeouts_multiple() {
+        let result = parse_stalled_timeouts("BAG_UNZIPPED=3600, S3_OBJECT_CREATED=600").unwrap();
+        assert_eq!(result.get("BAG_UNZIPPED"), Some(&3600));
+        assert_eq!(result.get("S3_OBJECT_CREATED"), Some(&600));
+    }
+    #[test]
+    fn parse_stalled_timeouts_invalid() {
+        assert!(parse_stalled_timeouts("BAG_UNZIPPED").is_err());
+        assert!(parse_stalled_timeouts("BAG_UNZIPPED=soon").is_err());
+    }
+}
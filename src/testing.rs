@@ -0,0 +1,101 @@
+use crate::changes::StatusChange;
+use crate::{CloudEvent, SharedClient};
+use serde_json::Value;
+use tokio_postgres::{Client, NoTls};
+
+/// The tables of a non-partitioned `sipin_sips`.
+pub const DDL: &str = include_str!("../ddl.sql");
+/// The partitioned `sipin_sips`, to replace the one in `DDL`.
+const DDL_PARTITIONED: &str = include_str!("../ddl_partitioned.sql");
+
+/// A successful event of type `type_field` for `correlation_id`, at `time`
+/// (RFC 3339).
+pub fn event(correlation_id: &str, type_field: &str, time: &str, data: Value) -> CloudEvent {
+    serde_json::from_value(serde_json::json!({
+        "type": type_field,
+        "source": "sipin",
+        "correlation_id": correlation_id,
+        "content_type": "application/json",
+        "time": time,
+        "datacontenttype": "application/json",
+        "outcome": "success",
+        "specversion": "1.0",
+        "id": "1",
+        "subject": correlation_id,
+        "data": data,
+    })).unwrap()
+}
+
+/// A change of SIP `abc` of CP `OR-rf5kf25` to `new_status`, by an event with
+/// `outcome`, that created the row.
+pub fn change(new_status: &str, outcome: &str) -> StatusChange {
+    StatusChange {
+        correlation_id: String::from("abc"),
+        old_status: None,
+        new_status: String::from(new_status),
+        cp_id: Some(String::from("OR-rf5kf25")),
+        pid: None,
+        time: "2024-05-20T10:00:00Z".parse().unwrap(),
+        outcome: String::from(outcome),
+        first_event_date: "2024-05-20T10:00:00Z".parse().unwrap(),
+        old_event_date: None,
+        stage: None,
+    }
+}
+
+async fn connect(params: &str) -> Client {
+    let (client, connection) = tokio_postgres::connect(params, NoTls).await
+        .expect("could not connect to the test database");
+    tokio::spawn(connection);
+    client
+}
+
+/// A new, empty database for the test `name`, with the tables of `ddl`.
+///
+/// `PULSAR2DB_TEST_POSTGRES` holds the connection parameters of a Postgres
+/// server on which the user may create databases, eg. `host=localhost
+/// user=admin password=admin dbname=postgres`. The tests that need it are
+/// marked `#[ignore]`, and run with `cargo test -- --ignored`. A database left
+/// behind by an earlier run of the test is dropped first.
+pub async fn database(name: &str, ddl: &str) -> Client {
+    let params = std::env::var("PULSAR2DB_TEST_POSTGRES").expect("PULSAR2DB_TEST_POSTGRES is not set");
+    let database = format!("pulsar2db_test_{}", name);
+    let admin = connect(&params).await;
+    admin.batch_execute(&format!("DROP DATABASE IF EXISTS {} WITH (FORCE)", database)).await
+        .expect("could not drop the test database");
+    admin.batch_execute(&format!("CREATE DATABASE {}", database)).await
+        .expect("could not create the test database");
+    let client = connect(&format!("{} dbname={}", params, database)).await;
+    client.batch_execute(ddl).await.expect("could not create the tables");
+    client
+}
+
+/// Like `database`, with the partitioned `sipin_sips` of
+/// `ddl_partitioned.sql`.
+pub async fn partitioned_database(name: &str) -> Client {
+    let client = database(name, DDL).await;
+    client.batch_execute("DROP TABLE public.sipin_sips").await.expect("could not drop sipin_sips");
+    client.batch_execute(DDL_PARTITIONED).await.expect("could not create the partitioned sipin_sips");
+    client
+}
+
+/// The connection parameters of the database of the test `name`, created by
+/// `database`.
+pub fn connection_string(name: &str) -> String {
+    let params = std::env::var("PULSAR2DB_TEST_POSTGRES").expect("PULSAR2DB_TEST_POSTGRES is not set");
+    format!("{} dbname=pulsar2db_test_{}", params, name)
+}
+
+/// Another connection to the database of the test `name`, created by
+/// `database`.
+pub async fn connection(name: &str) -> Client {
+    connect(&connection_string(name)).await
+}
+
+/// A `SharedClient` on the database of the test `name`, created by
+/// `database`.
+pub async fn shared_connection(name: &str) -> SharedClient {
+    let params = connection_string(name);
+    let client = connect(&params).await;
+    SharedClient::new(params, client)
+}
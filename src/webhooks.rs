@@ -0,0 +1,344 @@
+use std::collections::HashMap;
+use std::fs;
+use std::sync::Arc;
+use std::time::Duration;
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+use tokio::sync::Notify;
+use tokio_postgres::{Client, Transaction};
+use crate::changes::StatusChange;
+use crate::stalled::TERMINAL_STATUSES;
+
+/// Header holding the HMAC-SHA256 signature of the body, as `sha256=<hex>`.
+pub const SIGNATURE_HEADER: &str = "X-Pulsar2db-Signature";
+/// Header holding the id of the delivery, the same for every attempt.
+pub const DELIVERY_HEADER: &str = "X-Pulsar2db-Delivery";
+
+/// The webhook of a CP, configured in a JSON file, eg.:
+///
+/// ```json
+/// [{"cp_id": "OR-rf5kf25", "url": "https://example.com/hooks/sip", "secret": "..."}]
+/// ```
+#[derive(Deserialize, Debug, Clone)]
+pub struct Webhook {
+    pub cp_id: String,
+    pub url: String,
+    pub secret: String,
+}
+
+/// The webhooks, per `cp_id`.
+#[derive(Debug, Default)]
+pub struct Webhooks {
+    pub hooks: HashMap<String, Webhook>,
+}
+
+impl Webhooks {
+    pub fn load(path: &str) -> Result<Webhooks, anyhow::Error> {
+        let hooks: Vec<Webhook> = serde_json::from_str(&fs::read_to_string(path)?)?;
+        Ok(Webhooks {
+            hooks: hooks.into_iter().map(|hook| (hook.cp_id.clone(), hook)).collect(),
+        })
+    }
+
+    /// The webhook to call for a change: the one of its CP, if the SIP
+    /// reached a terminal status or the event reported a failure.
+    pub fn for_change(&self, change: &StatusChange) -> Option<&Webhook> {
+        let terminal = TERMINAL_STATUSES.contains(&change.new_status.as_str()) || change.outcome != "success";
+        match terminal {
+            true => self.hooks.get(change.cp_id.as_deref()?),
+            false => None,
+        }
+    }
+}
+
+/// The signature of a body, as sent in `SIGNATURE_HEADER`.
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// The delay before retrying a delivery that failed `attempts` times:
+/// `backoff`, doubling with every attempt, up to an hour.
+pub fn retry_delay(backoff: Duration, attempts: u32) -> Duration {
+    let factor = 2u32.saturating_pow(attempts.saturating_sub(1));
+    backoff.saturating_mul(factor).min(Duration::from_secs(3600))
+}
+
+/// Add a delivery for a change to the log, as part of the transaction that
+/// caused it.
+pub async fn enqueue(transaction: &Transaction<'_>, webhook: &Webhook, change: &StatusChange) -> Result<u64, tokio_postgres::Error> {
+    let payload = serde_json::to_value(change).unwrap_or_default();
+    transaction.execute(
+        "INSERT INTO sipin_webhook_deliveries (correlation_id, cp_id, url, payload)
+        VALUES ($1, $2, $3, $4)",
+        &[&change.correlation_id, &webhook.cp_id, &webhook.url, &payload],
+    ).await
+}
+
+/// POST a signed payload to a webhook. Returns the status code of the
+/// response, or an error if there was none or it wasn't a success.
+pub async fn deliver(http: &reqwest::Client, url: &str, secret: &str, id: i64, body: String) -> Result<u16, anyhow::Error> {
+    let response = http.post(url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .header(SIGNATURE_HEADER, sign(secret, body.as_bytes()))
+        .header(DELIVERY_HEADER, id.to_string())
+        .body(body)
+        .send()
+        .await?;
+    let status = response.status();
+    match status.is_success() {
+        true => Ok(status.as_u16()),
+        false => Err(anyhow::anyhow!("webhook responded with {}", status)),
+    }
+}
+
+/// A delivery claimed by `WebhookSender::claim`.
+#[derive(Debug)]
+struct Claimed {
+    id: i64,
+    cp_id: String,
+    url: String,
+    payload: String,
+    attempts: u32,
+}
+
+/// Delivers the pending webhook calls in `sipin_webhook_deliveries`, which
+/// doubles as the delivery log.
+///
+/// Every delivery is claimed before it is attempted, in a statement of its
+/// own: the attempt is counted and the delivery is kept from other instances
+/// for `lease`, after which it is retried if the result never got stored. The
+/// call itself is made outside of any transaction, and its result is stored
+/// on its own.
+///
+/// Failed deliveries are retried after `retry_delay`, until `max_attempts`
+/// attempts were made, after which they are marked as failed.
+pub struct WebhookSender {
+    pub client: Client,
+    pub http: reqwest::Client,
+    pub webhooks: Arc<Webhooks>,
+    pub wake: Arc<Notify>,
+    pub interval: Duration,
+    pub max_attempts: u32,
+    pub backoff: Duration,
+    /// How long a claimed delivery is left alone, longer than the timeout of
+    /// `http`.
+    pub lease: Duration,
+}
+
+impl WebhookSender {
+    /// Wait until new deliveries were committed, or the interval passed.
+    pub async fn wait(&self) {
+        tokio::select! {
+            _ = self.wake.notified() => (),
+            _ = tokio::time::sleep(self.interval) => (),
+        }
+    }
+
+    /// Attempt the deliveries that are due, one at a time. Returns the number
+    /// of attempts.
+    pub async fn send_due(&self) -> Result<usize, tokio_postgres::Error> {
+        let mut count = 0;
+        while let Some(delivery) = self.claim().await? {
+            count += 1;
+            // The secret is looked up at send time, so it isn't stored.
+            let result = match self.webhooks.hooks.get(&delivery.cp_id) {
+                Some(webhook) => deliver(&self.http, &delivery.url, &webhook.secret, delivery.id, delivery.payload).await,
+                None => Err(anyhow::anyhow!("no webhook configured for {}", delivery.cp_id)),
+            };
+            match result {
+                Ok(status) => {
+                    log::info!("Delivered webhook {} to {}", delivery.id, delivery.url);
+                    self.client.execute(
+                        "UPDATE sipin_webhook_deliveries
+                        SET status = 'delivered', response_status = $2, last_error = NULL, delivered_at = now()
+                        WHERE id = $1", &[&delivery.id, &(status as i32)],
+                    ).await?;
+                },
+                Err(error) => {
+                    let status = match delivery.attempts >= self.max_attempts {
+                        true => "failed",
+                        false => "pending",
+                    };
+                    log::warn!("Could not deliver webhook {} to {} (attempt {}): {:?}", delivery.id, delivery.url, delivery.attempts, error);
+                    let next_attempt_at = chrono::Utc::now() + to_chrono(retry_delay(self.backoff, delivery.attempts));
+                    self.client.execute(
+                        "UPDATE sipin_webhook_deliveries
+                        SET status = $2, last_error = $3, next_attempt_at = $4
+                        WHERE id = $1", &[&delivery.id, &status, &error.to_string(), &next_attempt_at],
+                    ).await?;
+                },
+            }
+        }
+        Ok(count)
+    }
+
+    /// Claim the first delivery that is due, if any, counting the attempt.
+    async fn claim(&self) -> Result<Option<Claimed>, tokio_postgres::Error> {
+        let lease = chrono::Utc::now() + to_chrono(self.lease);
+        let row = self.client.query_opt(
+            "UPDATE sipin_webhook_deliveries
+            SET attempts = attempts + 1, next_attempt_at = $1
+            WHERE id = (
+                SELECT id FROM sipin_webhook_deliveries
+                WHERE status = 'pending' AND next_attempt_at <= now()
+                ORDER BY id
+                LIMIT 1
+                FOR UPDATE SKIP LOCKED
+            )
+            RETURNING id, cp_id, url, payload::text, attempts", &[&lease],
+        ).await?;
+        Ok(row.map(|row| Claimed {
+            id: row.get(0),
+            cp_id: row.get(1),
+            url: row.get(2),
+            payload: row.get(3),
+            attempts: row.get::<_, i32>(4) as u32,
+        }))
+    }
+}
+
+fn to_chrono(duration: Duration) -> chrono::Duration {
+    chrono::Duration::from_std(duration).unwrap_or_else(|_| chrono::Duration::hours(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+    use axum::{http::{HeaderMap, StatusCode}, routing::post, Router};
+    use tokio::sync::mpsc;
+    use crate::testing::change;
+
+
+    fn webhooks() -> Webhooks {
+        let hook = Webhook {
+            cp_id: String::from("OR-rf5kf25"),
+            url: String::from("http://localhost/hook"),
+            secret: String::from("secret"),
+        };
+        Webhooks { hooks: HashMap::from([(hook.cp_id.clone(), hook)]) }
+    }
+
+    /// A local HTTP stub answering with `status`, passing on the signature
+    /// header and body of every request it receives.
+    async fn stub(status: StatusCode) -> (String, mpsc::UnboundedReceiver<(String, String)>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let app = Router::new().route("/hook", post(move |headers: HeaderMap, body: String| async move {
+            let signature = headers.get(SIGNATURE_HEADER).and_then(|value| value.to_str().ok()).unwrap_or_default();
+            let _ = tx.send((signature.to_string(), body));
+            status
+        }));
+        let server = axum::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(app.into_make_service());
+        let url = format!("http://{}/hook", server.local_addr());
+        tokio::spawn(server);
+        (url, rx)
+    }
+
+    fn sender(client: Client) -> WebhookSender {
+        WebhookSender {
+            client,
+            http: reqwest::Client::new(),
+            webhooks: Arc::new(webhooks()),
+            wake: Arc::new(Notify::new()),
+            interval: Duration::from_secs(1),
+            max_attempts: 2,
+            backoff: Duration::from_secs(60),
+            lease: Duration::from_secs(60),
+        }
+    }
+
+    async fn add(client: &Client, url: &str) {
+        client.execute(
+            "INSERT INTO sipin_webhook_deliveries (correlation_id, cp_id, url, payload)
+            VALUES ('abc', 'OR-rf5kf25', $1, '{\"new_status\": \"AIP_DELIVERED_TO_MAM\"}')", &[&url],
+        ).await.unwrap();
+    }
+
+    async fn delivery(client: &Client) -> (String, i32, Option<i32>) {
+        let row = client.query_one("SELECT status, attempts, response_status FROM sipin_webhook_deliveries", &[]).await.unwrap();
+        (row.get(0), row.get(1), row.get(2))
+    }
+
+    #[test]
+    fn for_change_on_terminal_status_or_failure() {
+        let webhooks = webhooks();
+        assert!(webhooks.for_change(&change("AIP_DELIVERED_TO_MAM", "success")).is_some());
+        assert!(webhooks.for_change(&change("BAG_VALIDATED", "fail")).is_some());
+        assert!(webhooks.for_change(&change("BAG_VALIDATED", "success")).is_none());
+        let mut other = change("AIP_DELIVERED_TO_MAM", "success");
+        other.cp_id = Some(String::from("OR-w66976m"));
+        assert!(webhooks.for_change(&other).is_none());
+    }
+    #[test]
+    fn sign_known_value() {
+        // echo -n 'body' | openssl dgst -sha256 -hmac secret
+        assert_eq!(sign("secret", b"body"), "sha256=dc46983557fea127b43af721467eb9b3fde2338fe3e14f51952aa8478c13d355");
+    }
+    #[test]
+    fn retry_delay_doubles_up_to_an_hour() {
+        let backoff = Duration::from_secs(10);
+        assert_eq!(retry_delay(backoff, 1), Duration::from_secs(10));
+        assert_eq!(retry_delay(backoff, 3), Duration::from_secs(40));
+        assert_eq!(retry_delay(backoff, 20), Duration::from_secs(3600));
+    }
+    #[tokio::test]
+    async fn deliver_signed_payload() {
+        let (url, mut requests) = stub(StatusCode::OK).await;
+        let status = deliver(&reqwest::Client::new(), &url, "secret", 1, String::from("body")).await.unwrap();
+        assert_eq!(status, 200);
+        let (signature, body) = requests.recv().await.unwrap();
+        assert_eq!(body, "body");
+        assert_eq!(signature, sign("secret", b"body"));
+    }
+    #[tokio::test]
+    #[ignore = "needs PULSAR2DB_TEST_POSTGRES"]
+    async fn send_due_delivers() {
+        let client = crate::testing::database("send_due_delivers", crate::testing::DDL).await;
+        let (url, mut requests) = stub(StatusCode::OK).await;
+        add(&client, &url).await;
+        let sender = sender(client);
+        assert_eq!(sender.send_due().await.unwrap(), 1);
+        assert_eq!(delivery(&sender.client).await, (String::from("delivered"), 1, Some(200)));
+        let (signature, body) = requests.recv().await.unwrap();
+        assert_eq!(signature, sign("secret", body.as_bytes()));
+        assert_eq!(sender.send_due().await.unwrap(), 0);
+    }
+    #[tokio::test]
+    #[ignore = "needs PULSAR2DB_TEST_POSTGRES"]
+    async fn send_due_retries_until_failed() {
+        let client = crate::testing::database("send_due_retries_until_failed", crate::testing::DDL).await;
+        let (url, _requests) = stub(StatusCode::INTERNAL_SERVER_ERROR).await;
+        add(&client, &url).await;
+        let sender = sender(client);
+        assert_eq!(sender.send_due().await.unwrap(), 1);
+        assert_eq!(delivery(&sender.client).await, (String::from("pending"), 1, None));
+        // Not due before the backoff passed.
+        assert_eq!(sender.send_due().await.unwrap(), 0);
+        sender.client.execute("UPDATE sipin_webhook_deliveries SET next_attempt_at = now()", &[]).await.unwrap();
+        assert_eq!(sender.send_due().await.unwrap(), 1);
+        assert_eq!(delivery(&sender.client).await, (String::from("failed"), 2, None));
+    }
+    #[tokio::test]
+    #[ignore = "needs PULSAR2DB_TEST_POSTGRES"]
+    async fn send_due_skips_claimed() {
+        let name = "send_due_skips_claimed";
+        let client = crate::testing::database(name, crate::testing::DDL).await;
+        let (url, mut requests) = stub(StatusCode::OK).await;
+        add(&client, &url).await;
+        // Another instance claimed it, and crashed or is still calling.
+        let other = sender(crate::testing::connection(name).await);
+        assert!(other.claim().await.unwrap().is_some());
+        let sender = sender(client);
+        assert_eq!(sender.send_due().await.unwrap(), 0);
+        assert!(requests.try_recv().is_err());
+        assert_eq!(delivery(&sender.client).await, (String::from("pending"), 1, None));
+    }
+    #[tokio::test]
+    async fn deliver_fails_on_error_response() {
+        let (url, _requests) = stub(StatusCode::INTERNAL_SERVER_ERROR).await;
+        assert!(deliver(&reqwest::Client::new(), &url, "secret", 1, String::from("body")).await.is_err());
+    }
+}
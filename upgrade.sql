@@ -0,0 +1,138 @@
+-- Upgrades the tables of an existing installation to the current ddl.sql.
+--
+-- Every statement only adds what is missing, so the script can be run on a
+-- database of any earlier version, and again. Run it before starting a new
+-- version of the service, eg.:
+-- psql -v ON_ERROR_STOP=1 -f upgrade.sql
+--
+-- Adding checksum_mismatch, a stored generated column, rewrites sipin_sips.
+-- A serial4 row_id is left alone, see ddl.sql to migrate it.
+
+-- public.sipin_sips columns and indexes
+
+ALTER TABLE public.sipin_sips ADD COLUMN IF NOT EXISTS checksum_mismatch bool GENERATED ALWAYS AS (lower(trim(md5_hash_essence_manifest)) <> lower(trim(md5_hash_essence_sidecar))) STORED;
+ALTER TABLE public.sipin_sips ADD COLUMN IF NOT EXISTS sip_profile text NULL;
+ALTER TABLE public.sipin_sips ADD COLUMN IF NOT EXISTS stalled bool NOT NULL DEFAULT false;
+ALTER TABLE public.sipin_sips ADD COLUMN IF NOT EXISTS resolution text NULL;
+CREATE INDEX IF NOT EXISTS sipin_sips_status_last_event_date_idx ON public.sipin_sips USING btree (status, last_event_date);
+CREATE INDEX IF NOT EXISTS sipin_sips_cp_id_first_event_date_idx ON public.sipin_sips USING btree (cp_id, first_event_date);
+CREATE INDEX IF NOT EXISTS sipin_sips_local_id_idx ON public.sipin_sips USING btree (local_id);
+
+COMMENT ON COLUMN public.sipin_sips.checksum_mismatch IS 'True if the manifest and sidecar MD5 hashes differ. NULL if either is unknown.';
+COMMENT ON COLUMN public.sipin_sips.sip_profile IS 'The profile of the SIP, as determined by the mh-sip.create event.';
+COMMENT ON COLUMN public.sipin_sips.stalled IS 'True when no new event arrived within the timeout for the current status.';
+COMMENT ON COLUMN public.sipin_sips.resolution IS 'Set manually to resolved or ignored: the SIP is no longer flagged as stalled.';
+
+-- public.sipin_sip_stages definition
+
+CREATE TABLE IF NOT EXISTS public.sipin_sip_stages (
+	correlation_id text NOT NULL, -- The correlation_id of the SIP-delivery.
+	stage text NOT NULL, -- The pipeline stage, eg. unzip or validate_xsd.
+	event_type text NOT NULL, -- Type of the event that marked the end of the stage.
+	event_date timestamptz NOT NULL, -- Datetime at which the stage ended.
+	outcome text NULL, -- Outcome of the stage, as reported by the event.
+	CONSTRAINT sipin_sip_stages_pkey PRIMARY KEY (correlation_id, stage)
+);
+CREATE INDEX IF NOT EXISTS sipin_sip_stages_event_date_idx ON public.sipin_sip_stages USING btree (event_date);
+
+-- Column comments
+
+COMMENT ON COLUMN public.sipin_sip_stages.correlation_id IS 'The correlation_id of the SIP-delivery.';
+COMMENT ON COLUMN public.sipin_sip_stages.stage IS 'The pipeline stage, eg. unzip or validate_xsd.';
+COMMENT ON COLUMN public.sipin_sip_stages.event_type IS 'Type of the event that marked the end of the stage.';
+COMMENT ON COLUMN public.sipin_sip_stages.event_date IS 'Datetime at which the stage ended.';
+COMMENT ON COLUMN public.sipin_sip_stages.outcome IS 'Outcome of the stage, as reported by the event.';
+
+-- public.sipin_cp_mappings definition
+
+CREATE TABLE IF NOT EXISTS public.sipin_cp_mappings (
+	bucket text NOT NULL, -- Bucket in which the CP delivers its SIPs.
+	key_prefix text NOT NULL DEFAULT '', -- Prefix of the keys of the CP's SIPs within the bucket. Empty for the whole bucket.
+	cp_id text NOT NULL, -- The ID for the CP within meemoo. Also known as OR-id.
+	CONSTRAINT sipin_cp_mappings_pkey PRIMARY KEY (bucket, key_prefix)
+);
+
+-- Column comments
+
+COMMENT ON COLUMN public.sipin_cp_mappings.bucket IS 'Bucket in which the CP delivers its SIPs.';
+COMMENT ON COLUMN public.sipin_cp_mappings.key_prefix IS 'Prefix of the keys of the CP''s SIPs within the bucket. Empty for the whole bucket.';
+COMMENT ON COLUMN public.sipin_cp_mappings.cp_id IS 'The ID for the CP within meemoo. Also known as OR-id.';
+
+-- public.sipin_outbox definition
+
+CREATE TABLE IF NOT EXISTS public.sipin_outbox (
+	id bigserial NOT NULL,
+	topic text NOT NULL, -- Pulsar topic on which the event is to be published.
+	payload jsonb NOT NULL, -- The CloudEvent.
+	created_at timestamptz NOT NULL DEFAULT now(), -- Datetime at which the event was added, in the transaction that caused it.
+	published_at timestamptz NULL, -- Datetime at which the broker acknowledged the event. NULL while pending.
+	CONSTRAINT sipin_outbox_pkey PRIMARY KEY (id)
+);
+CREATE INDEX IF NOT EXISTS sipin_outbox_pending_idx ON public.sipin_outbox USING btree (id) WHERE published_at IS NULL;
+CREATE INDEX IF NOT EXISTS sipin_outbox_published_at_idx ON public.sipin_outbox USING btree (published_at);
+
+-- Column comments
+
+COMMENT ON COLUMN public.sipin_outbox.topic IS 'Pulsar topic on which the event is to be published.';
+COMMENT ON COLUMN public.sipin_outbox.payload IS 'The CloudEvent.';
+COMMENT ON COLUMN public.sipin_outbox.created_at IS 'Datetime at which the event was added, in the transaction that caused it.';
+COMMENT ON COLUMN public.sipin_outbox.published_at IS 'Datetime at which the broker acknowledged the event. NULL while pending.';
+
+-- public.sipin_webhook_deliveries definition
+
+CREATE TABLE IF NOT EXISTS public.sipin_webhook_deliveries (
+	id bigserial NOT NULL,
+	correlation_id text NOT NULL, -- The correlation_id of the SIP-delivery.
+	cp_id text NOT NULL, -- The CP whose webhook is called.
+	url text NOT NULL, -- URL of the webhook.
+	payload jsonb NOT NULL, -- The status change that is delivered.
+	status text NOT NULL DEFAULT 'pending', -- pending, delivered or failed (after the maximum number of attempts).
+	attempts int4 NOT NULL DEFAULT 0, -- Number of delivery attempts so far.
+	next_attempt_at timestamptz NOT NULL DEFAULT now(), -- Datetime of the next attempt, while pending.
+	response_status int4 NULL, -- HTTP status of the successful attempt.
+	last_error text NULL, -- Error of the last failed attempt.
+	created_at timestamptz NOT NULL DEFAULT now(), -- Datetime at which the delivery was added.
+	delivered_at timestamptz NULL, -- Datetime of the successful attempt.
+	CONSTRAINT sipin_webhook_deliveries_pkey PRIMARY KEY (id)
+);
+CREATE INDEX IF NOT EXISTS sipin_webhook_deliveries_pending_idx ON public.sipin_webhook_deliveries USING btree (next_attempt_at) WHERE status = 'pending';
+CREATE INDEX IF NOT EXISTS sipin_webhook_deliveries_correlation_id_idx ON public.sipin_webhook_deliveries USING btree (correlation_id);
+
+-- Column comments
+
+COMMENT ON COLUMN public.sipin_webhook_deliveries.correlation_id IS 'The correlation_id of the SIP-delivery.';
+COMMENT ON COLUMN public.sipin_webhook_deliveries.cp_id IS 'The CP whose webhook is called.';
+COMMENT ON COLUMN public.sipin_webhook_deliveries.url IS 'URL of the webhook.';
+COMMENT ON COLUMN public.sipin_webhook_deliveries.payload IS 'The status change that is delivered.';
+COMMENT ON COLUMN public.sipin_webhook_deliveries.status IS 'pending, delivered or failed (after the maximum number of attempts).';
+COMMENT ON COLUMN public.sipin_webhook_deliveries.attempts IS 'Number of delivery attempts so far.';
+COMMENT ON COLUMN public.sipin_webhook_deliveries.next_attempt_at IS 'Datetime of the next attempt, while pending.';
+COMMENT ON COLUMN public.sipin_webhook_deliveries.response_status IS 'HTTP status of the successful attempt.';
+COMMENT ON COLUMN public.sipin_webhook_deliveries.last_error IS 'Error of the last failed attempt.';
+COMMENT ON COLUMN public.sipin_webhook_deliveries.created_at IS 'Datetime at which the delivery was added.';
+COMMENT ON COLUMN public.sipin_webhook_deliveries.delivered_at IS 'Datetime of the successful attempt.';
+
+-- public.sipin_sip_audit definition
+
+CREATE TABLE IF NOT EXISTS public.sipin_sip_audit (
+	id bigserial NOT NULL,
+	correlation_id text NOT NULL, -- The correlation_id of the corrected SIP-delivery.
+	action text NOT NULL, -- set_status, resolve, ignore or reopen.
+	actor text NOT NULL, -- Who made the correction.
+	reason text NOT NULL, -- Why the correction was made.
+	before jsonb NOT NULL, -- The sipin_sips row before the correction.
+	after jsonb NOT NULL, -- The sipin_sips row after the correction.
+	created_at timestamptz NOT NULL DEFAULT now(), -- Datetime of the correction.
+	CONSTRAINT sipin_sip_audit_pkey PRIMARY KEY (id)
+);
+CREATE INDEX IF NOT EXISTS sipin_sip_audit_correlation_id_idx ON public.sipin_sip_audit USING btree (correlation_id);
+
+-- Column comments
+
+COMMENT ON COLUMN public.sipin_sip_audit.correlation_id IS 'The correlation_id of the corrected SIP-delivery.';
+COMMENT ON COLUMN public.sipin_sip_audit.action IS 'set_status, resolve, ignore or reopen.';
+COMMENT ON COLUMN public.sipin_sip_audit.actor IS 'Who made the correction.';
+COMMENT ON COLUMN public.sipin_sip_audit.reason IS 'Why the correction was made.';
+COMMENT ON COLUMN public.sipin_sip_audit.before IS 'The sipin_sips row before the correction.';
+COMMENT ON COLUMN public.sipin_sip_audit.after IS 'The sipin_sips row after the correction.';
+COMMENT ON COLUMN public.sipin_sip_audit.created_at IS 'Datetime of the correction.';
@@ -0,0 +1,51 @@
+-- Reporting views over sipin_sips and sipin_sip_stages.
+--
+-- These views are created, and replaced when they change, by pulsar2db at
+-- startup. Since `CREATE OR REPLACE VIEW` can only add columns, a view that
+-- loses columns needs to be dropped by hand.
+
+-- Time spent in every stage, ie. since the end of the previous stage.
+CREATE OR REPLACE VIEW public.sipin_sip_stage_durations AS
+SELECT
+	st.correlation_id,
+	s.cp_id,
+	st.stage,
+	st.outcome,
+	st.event_date,
+	st.event_date - lag(st.event_date) OVER (PARTITION BY st.correlation_id ORDER BY st.event_date) AS duration
+FROM public.sipin_sip_stages st
+LEFT JOIN public.sipin_sips s ON s.correlation_id = st.correlation_id;
+
+-- Number of SIPs per current status, per CP, per day of delivery.
+CREATE OR REPLACE VIEW public.sipin_sips_per_status AS
+SELECT
+	date_trunc('day', first_event_date)::date AS day,
+	cp_id,
+	status,
+	count(*) AS sips
+FROM public.sipin_sips
+GROUP BY 1, 2, 3;
+
+-- Number of SIPs and bytes delivered per CP, per day of delivery.
+CREATE OR REPLACE VIEW public.sipin_sips_throughput AS
+SELECT
+	date_trunc('day', first_event_date)::date AS day,
+	cp_id,
+	count(*) AS sips,
+	coalesce(sum(bag_filesize), 0) AS bag_bytes,
+	coalesce(sum(essence_filesize), 0) AS essence_bytes
+FROM public.sipin_sips
+GROUP BY 1, 2;
+
+-- Failure rate per pipeline stage, per CP, per day.
+CREATE OR REPLACE VIEW public.sipin_sip_stage_failures AS
+SELECT
+	date_trunc('day', st.event_date)::date AS day,
+	s.cp_id,
+	st.stage,
+	count(*) AS sips,
+	count(*) FILTER (WHERE st.outcome IS DISTINCT FROM 'success') AS failed,
+	round(count(*) FILTER (WHERE st.outcome IS DISTINCT FROM 'success')::numeric / count(*), 4) AS failure_rate
+FROM public.sipin_sip_stages st
+LEFT JOIN public.sipin_sips s ON s.correlation_id = st.correlation_id
+GROUP BY 1, 2, 3;